@@ -1,14 +1,14 @@
 use std::fs::{self, File};
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::*;
 use log::*;
-use memmap2::Mmap;
-use rayon::prelude::*;
 use structopt::*;
 
 use piz::read::*;
+use piz::result::ZipResult;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "unzip", about = "Dumps a .zip file into the current directory")]
@@ -25,6 +25,14 @@ struct Opt {
     #[structopt(short = "n", long)]
     dry_run: bool,
 
+    /// Tests the archive's integrity instead of extracting it.
+    #[structopt(short, long)]
+    test: bool,
+
+    /// The number of threads to extract with (defaults to one per core)
+    #[structopt(short = "j", long)]
+    threads: Option<usize>,
+
     #[structopt(name("ZIP file"))]
     zip_path: PathBuf,
 }
@@ -41,19 +49,22 @@ fn main() -> Result<()> {
             .with_context(|| format!("Couldn't set working directory to {}", chto.display()))?;
     }
 
-    info!("Memory mapping {:#?}", &args.zip_path);
-    let zip_file = File::open(&args.zip_path).context("Couldn't open zip file")?;
-    let mapping = unsafe { Mmap::map(&zip_file).context("Couldn't mmap zip file")? };
-
-    let archive = ZipArchive::with_prepended_data(&mapping)
+    info!("Opening {:#?}", &args.zip_path);
+    // SAFETY: We don't modify the archive while we're unzipping it.
+    // (Don't do that from some other process either!)
+    let file =
+        unsafe { ArchiveFile::open_mapped(&args.zip_path) }.context("Couldn't load archive")?;
+    let archive = OwnedZipArchive::with_prepended_data(Arc::new(file))
         .context("Couldn't load archive")?
         .0;
-    let tree = as_tree(archive.entries())?;
+    let tree = archive.tree();
 
     if args.dry_run {
-        print_tree(&tree)
+        print_tree(tree)
+    } else if args.test {
+        test_zip(archive.archive())
     } else {
-        read_zip(&tree, &archive)
+        read_zip(tree, archive.archive(), args.threads)
     }
 }
 
@@ -64,16 +75,39 @@ fn print_tree(tree: &DirectoryContents) -> Result<()> {
     Ok(())
 }
 
-fn read_zip(tree: &DirectoryContents, archive: &ZipArchive) -> Result<()> {
-    tree.files().par_bridge().try_for_each(|entry| {
+fn test_zip(archive: &ZipArchive) -> Result<()> {
+    let report = archive.test();
+    for failure in &report.failures {
+        println!("{}: {}", failure.entry.path, failure.error);
+    }
+    ensure!(
+        report.is_ok(),
+        "{} of {} entries failed",
+        report.failures.len(),
+        report.entries_tested
+    );
+    println!("No errors in {} entries", report.entries_tested);
+    Ok(())
+}
+
+fn read_zip(tree: &DirectoryContents, archive: &ZipArchive, threads: Option<usize>) -> Result<()> {
+    let mut options = ParallelOptions::new().progress(|p| {
+        debug!(
+            "{}/{} files, {}/{} bytes",
+            p.entries_done, p.entries_total, p.bytes_done, p.bytes_total
+        )
+    });
+    if let Some(threads) = threads {
+        options = options.threads(threads);
+    }
+
+    archive.read_parallel(tree.files(), &options, |entry, reader| {
         if let Some(parent) = entry.path.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("Couldn't create directory {}", parent))?;
+            fs::create_dir_all(parent)?;
         }
-        let mut reader = archive.read(entry)?;
-        let mut sink = File::create(&*entry.path)
-            .with_context(|| format!("Couldn't create file {}", entry.path))?;
-        io::copy(&mut reader, &mut sink)?;
-        Ok(())
-    })
+        let mut sink = File::create(&*entry.path)?;
+        io::copy(reader, &mut sink)?;
+        ZipResult::Ok(())
+    })?;
+    Ok(())
 }
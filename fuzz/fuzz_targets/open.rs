@@ -0,0 +1,10 @@
+//! Parses the archive's end of central directory records and central directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use piz::ZipArchive;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ZipArchive::with_prepended_data(data);
+});
@@ -0,0 +1,35 @@
+//! Reads every entry every way we can.
+//!
+//! Readers stop at an entry's declared size, and Deflate can only expand
+//! so much, so each entry's output is bounded by the input's size.
+//! Bounding the number of entries keeps overlapping ones from adding up.
+
+#![no_main]
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use libfuzzer_sys::fuzz_target;
+use piz::read::*;
+
+fuzz_target!(|data: &[u8]| {
+    let options = OpenOptions::new().max_entries(64);
+    let Ok((archive, _offset)) = ZipArchive::with_options(data, &options) else {
+        return;
+    };
+    let _ = archive.test();
+    for entry in archive.entries() {
+        if let Ok(mut reader) = archive.read(entry) {
+            let _ = io::copy(&mut reader, &mut io::sink());
+        }
+        let _ = archive.read_to_vec(entry);
+        let _ = archive.stored_bytes_checked(entry);
+        if let Ok(mut reader) = archive.read_seekable(entry) {
+            let _ = reader.seek(SeekFrom::End(-1));
+            let _ = reader.read(&mut [0; 1]);
+            let _ = reader.seek(SeekFrom::Start(0));
+            let _ = reader.read(&mut [0; 1]);
+        }
+    }
+    // Checking limits (and looking for overlapping entries) shouldn't crash either.
+    let _ = archive.with_limits(Limits::new().max_total_size(1024).max_ratio(100));
+});
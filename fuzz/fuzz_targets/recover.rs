@@ -0,0 +1,18 @@
+//! Scans the archive for local file headers, then reads whatever it salvaged.
+
+#![no_main]
+
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+use piz::ZipArchive;
+
+fuzz_target!(|data: &[u8]| {
+    let (archive, _report) = ZipArchive::recover(data);
+    for entry in archive.entries() {
+        if let Ok(mut reader) = archive.read(entry) {
+            let _ = io::copy(&mut reader, &mut io::sink());
+        }
+        let _ = archive.read_to_vec(entry);
+    }
+});
@@ -0,0 +1,34 @@
+//! Organizes the archive's entries into a tree (sanitized and not),
+//! then looks each one back up.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use piz::read::*;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok((archive, _offset)) = ZipArchive::with_prepended_data(data) else {
+        return;
+    };
+    let sanitized = sanitize_entries(archive.entries()).map(|(entries, _rewrites)| entries);
+    let sets = [Ok(archive.entries().to_vec()), sanitized];
+    for entries in sets.iter().flatten() {
+        for options in [
+            TreeOptions::new(),
+            TreeOptions::new()
+                .duplicates(DuplicatePolicy::KeepAll)
+                .reject_case_collisions(true),
+        ] {
+            let Ok(tree) = as_tree_with(entries, &options) else {
+                continue;
+            };
+            for entry in tree.traverse() {
+                let _ = tree.lookup_versions(&entry.metadata().path);
+            }
+            let index = CaseInsensitiveIndex::new(&tree);
+            for file in tree.files() {
+                let _ = index.lookup(&file.path);
+            }
+        }
+    }
+});
@@ -1,4 +1,4 @@
-//! Helper module to compute a CRC32 checksum
+//! Helper module to compute a CRC32 checksum (and check a file's size)
 //!
 //! Borrowed from zip-rs:
 //! <https://github.com/mvdnes/zip-rs/commit/b3c836d9c32efa120cdd5366280f940d3c3b985c>
@@ -8,37 +8,68 @@ use std::io::prelude::*;
 
 use crc32fast::Hasher;
 
-/// Reader that validates the CRC32 when it reaches the EOF.
+use crate::result::ZipError;
+
+/// Reader that validates the size and CRC32 when it reaches the EOF.
+///
+/// Reading more than the provided size fails right away,
+/// instead of waiting for the end of a (possibly endless) stream.
 pub struct Crc32Reader<R> {
     inner: R,
     hasher: Hasher,
     provided_checksum: u32,
+    provided_size: u64,
+    bytes_read: u64,
 }
 
 impl<R> Crc32Reader<R> {
-    pub fn new(inner: R, provided_checksum: u32) -> Crc32Reader<R> {
+    pub fn new(inner: R, provided_checksum: u32, provided_size: u64) -> Crc32Reader<R> {
         Crc32Reader {
             inner,
             hasher: Hasher::new(),
             provided_checksum,
+            provided_size,
+            bytes_read: 0,
         }
     }
 
-    /// Returns true if the final checksum matches the one provided by `new()`
-    fn check_matches(&self) -> bool {
-        self.provided_checksum == self.hasher.clone().finalize()
+    /// Fails if the final checksum doesn't match the one provided by `new()`
+    fn check_matches(&self) -> io::Result<()> {
+        let actual = self.hasher.clone().finalize();
+        if actual != self.provided_checksum {
+            return Err(ZipError::ChecksumMismatch {
+                expected: self.provided_checksum,
+                actual,
+            }
+            .into());
+        }
+        Ok(())
     }
 }
 
 impl<R: Read> Read for Crc32Reader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let count = match self.inner.read(buf) {
-            Ok(0) if !buf.is_empty() && !self.check_matches() => {
-                return Err(io::Error::new(io::ErrorKind::Other, "Invalid checksum"))
+            Ok(0) if !buf.is_empty() && self.bytes_read < self.provided_size => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "File is shorter than its declared size",
+                ))
+            }
+            Ok(0) if !buf.is_empty() => {
+                self.check_matches()?;
+                0
             }
             Ok(n) => n,
             Err(e) => return Err(e),
         };
+        self.bytes_read += count as u64;
+        if self.bytes_read > self.provided_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "File is longer than its declared size",
+            ));
+        }
         self.hasher.update(&buf[0..count]);
         Ok(count)
     }
@@ -54,15 +85,19 @@ mod test {
         let data: &[u8] = b"";
         let mut buf = [0; 1];
 
-        let mut reader = Crc32Reader::new(data, 0);
+        let mut reader = Crc32Reader::new(data, 0, 0);
         assert_eq!(reader.read(&mut buf).unwrap(), 0);
 
-        let mut reader = Crc32Reader::new(data, 1);
-        assert!(reader
-            .read(&mut buf)
-            .unwrap_err()
-            .to_string()
-            .contains("Invalid checksum"));
+        let mut reader = Crc32Reader::new(data, 1, 0);
+        let error = reader.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            ZipError::from(error),
+            ZipError::ChecksumMismatch {
+                expected: 1,
+                actual: 0
+            }
+        ));
     }
 
     #[test]
@@ -70,7 +105,7 @@ mod test {
         let data: &[u8] = b"1234";
         let mut buf = [0; 1];
 
-        let mut reader = Crc32Reader::new(data, 0x9be3e0a3);
+        let mut reader = Crc32Reader::new(data, 0x9be3e0a3, 4);
         assert_eq!(reader.read(&mut buf).unwrap(), 1);
         assert_eq!(reader.read(&mut buf).unwrap(), 1);
         assert_eq!(reader.read(&mut buf).unwrap(), 1);
@@ -85,8 +120,29 @@ mod test {
         let data: &[u8] = b"1234";
         let mut buf = [0; 5];
 
-        let mut reader = Crc32Reader::new(data, 0x9be3e0a3);
+        let mut reader = Crc32Reader::new(data, 0x9be3e0a3, 4);
         assert_eq!(reader.read(&mut buf[..0]).unwrap(), 0);
         assert_eq!(reader.read(&mut buf).unwrap(), 4);
     }
+
+    #[test]
+    fn test_wrong_size() {
+        let data: &[u8] = b"1234";
+        let mut buf = [0; 2];
+
+        // Too long: fail as soon as we pass the declared size.
+        let mut reader = Crc32Reader::new(data, 0x9be3e0a3, 3);
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(
+            reader.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // Too short: fail at the end, even before checking the CRC.
+        let mut reader = Crc32Reader::new(data, 0x9be3e0a3, 5);
+        assert_eq!(
+            reader.read_to_end(&mut Vec::new()).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
 }
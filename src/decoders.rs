@@ -0,0 +1,89 @@
+//! User-provided decoders for compression methods piz doesn't know about
+//!
+//! The ZIP spec lists a couple dozen compression methods,
+//! and nothing stops people from inventing their own.
+//! Rather than build them all in, let callers bring their own.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// Decompresses files stored with some compression method
+///
+/// Register implementations with [`Decoders`], then pass those to
+/// [`ZipArchive::with_decoders()`].
+///
+/// ```no_run
+/// # use std::fs;
+/// # use std::io;
+/// # use piz::*;
+/// # use piz::read::*;
+/// /// Our own bespoke compression, method ID 0xf00d
+/// struct Snack;
+///
+/// impl Decoder for Snack {
+///     fn decode<'a>(
+///         &self,
+///         compressed: Box<dyn io::Read + Send + 'a>,
+///     ) -> io::Result<Box<dyn io::Read + Send + 'a>> {
+///         # fn snack_decoder<'a>(r: Box<dyn io::Read + Send + 'a>) -> Box<dyn io::Read + Send + 'a> { r }
+///         Ok(snack_decoder(compressed))
+///     }
+/// }
+///
+/// let bytes = fs::read("snacks.zip")?;
+/// let archive = ZipArchive::new(&bytes)?.with_decoders(Decoders::new().register(0xf00d, Snack));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`ZipArchive::with_decoders()`]: struct.ZipArchive.html#method.with_decoders
+pub trait Decoder: Send + Sync {
+    /// Wraps a reader of a file's compressed data in one that decompresses it.
+    ///
+    /// piz checks the output's size and CRC32, so decoders don't have to.
+    fn decode<'a>(
+        &self,
+        compressed: Box<dyn io::Read + Send + 'a>,
+    ) -> io::Result<Box<dyn io::Read + Send + 'a>>;
+}
+
+/// A set of [`Decoder`]s, keyed by the compression method they handle
+///
+/// Cheap to clone, so one set can be shared between many archives.
+/// Stored (0) and Deflate (8) files always use piz's own decoders.
+#[derive(Clone, Default)]
+pub struct Decoders {
+    decoders: HashMap<u16, Arc<dyn Decoder>>,
+}
+
+impl Decoders {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes files with the given compression method ID
+    /// (`CompressionMethod::Unsupported(method)`) using the given decoder.
+    ///
+    /// Stored and Deflate files always use piz's own decompression,
+    /// whose Deflate backend is picked at compile time with Cargo features.
+    pub fn register<D: Decoder + 'static>(mut self, method: u16, decoder: D) -> Self {
+        self.decoders.insert(method, Arc::new(decoder));
+        self
+    }
+
+    pub(crate) fn get(&self, method: u16) -> Option<&dyn Decoder> {
+        self.decoders.get(&method).map(|d| d.as_ref())
+    }
+}
+
+impl fmt::Debug for Decoders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut methods: Vec<_> = self.decoders.keys().collect();
+        methods.sort_unstable();
+        f.debug_struct("Decoders")
+            .field("methods", &methods)
+            .finish()
+    }
+}
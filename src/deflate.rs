@@ -0,0 +1,184 @@
+//! Deflate backends, picked with Cargo features
+//!
+//! Streaming goes through flate2, which uses miniz_oxide by default,
+//! or zlib-rs or zlib-ng with the `deflate-zlib-rs` or `deflate-zlib-ng` features.
+//! Decompressing a whole file into a buffer at once (as `read_to_vec()` does)
+//! uses the same backend, unless the `libdeflate` feature is enabled,
+//! since libdeflate is much faster at that (but can't stream).
+//!
+//! (Seekable readers always use miniz_oxide, which lets us checkpoint its state.)
+//!
+//! The choice is made at compile time, so [`Decompressor`] stays private;
+//! users with their own compression methods register a `Decoder` instead.
+
+use std::io;
+
+use flate2::read::DeflateDecoder;
+use flate2::{Decompress, FlushDecompress, Status};
+
+use crate::result::*;
+
+/// A Deflate implementation
+pub(crate) trait Decompressor: Sync {
+    /// Wraps a reader of `compressed_size` bytes of compressed data
+    /// in one that decompresses it, failing if the stream ends early.
+    fn reader<'a>(
+        &self,
+        compressed: Box<dyn io::Read + Send + 'a>,
+        compressed_size: u64,
+    ) -> Box<dyn io::Read + Send + 'a>;
+
+    /// Decompresses all of `compressed` into `out`,
+    /// failing if it doesn't fill `out` exactly
+    /// or the stream ends before `compressed` does.
+    fn decompress_into(&self, compressed: &[u8], out: &mut [u8]) -> ZipResult<()>;
+}
+
+/// Returns the Deflate implementation chosen by our features.
+pub(crate) fn deflate() -> &'static dyn Decompressor {
+    #[cfg(feature = "libdeflate")]
+    return &Libdeflate;
+    #[cfg(not(feature = "libdeflate"))]
+    return &Flate2;
+}
+
+fn wrong_size() -> ZipError {
+    ZipError::InvalidArchive("Deflate stream doesn't match the file's declared size")
+}
+
+fn truncated() -> ZipError {
+    ZipError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Deflate stream is truncated",
+    ))
+}
+
+fn trailing_data() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Deflate stream ends before the file's compressed data does",
+    )
+}
+
+fn corrupt() -> ZipError {
+    ZipError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Corrupt Deflate stream",
+    ))
+}
+
+/// flate2, with whatever backend its features picked
+struct Flate2;
+
+impl Decompressor for Flate2 {
+    fn reader<'a>(
+        &self,
+        compressed: Box<dyn io::Read + Send + 'a>,
+        compressed_size: u64,
+    ) -> Box<dyn io::Read + Send + 'a> {
+        Box::new(WholeStream {
+            decoder: DeflateDecoder::new(compressed),
+            compressed_size,
+        })
+    }
+
+    fn decompress_into(&self, compressed: &[u8], out: &mut [u8]) -> ZipResult<()> {
+        let mut decompress = Decompress::new(false);
+        let status = decompress
+            // (Not Finish, which makes some backends give up for good
+            // if the output doesn't fit, and we want to check for that below.)
+            .decompress(compressed, out, FlushDecompress::None)
+            .map_err(|_| corrupt())?;
+        match status {
+            Status::StreamEnd if decompress.total_out() != out.len() as u64 => Err(wrong_size()),
+            Status::StreamEnd if decompress.total_in() != compressed.len() as u64 => {
+                Err(ZipError::Io(trailing_data()))
+            }
+            Status::StreamEnd => Ok(()),
+            _ => {
+                // We stopped before the end of the stream.
+                // Did we run out of room for output, or did the input run dry?
+                let written = decompress.total_out();
+                let rest = &compressed[decompress.total_in() as usize..];
+                let _ = decompress.decompress(rest, &mut [0], FlushDecompress::None);
+                if decompress.total_out() > written {
+                    Err(wrong_size())
+                } else {
+                    Err(truncated())
+                }
+            }
+        }
+    }
+}
+
+/// libdeflate for whole files, flate2 for streaming
+#[cfg(feature = "libdeflate")]
+struct Libdeflate;
+
+#[cfg(feature = "libdeflate")]
+impl Decompressor for Libdeflate {
+    fn reader<'a>(
+        &self,
+        compressed: Box<dyn io::Read + Send + 'a>,
+        compressed_size: u64,
+    ) -> Box<dyn io::Read + Send + 'a> {
+        Flate2.reader(compressed, compressed_size)
+    }
+
+    fn decompress_into(&self, compressed: &[u8], out: &mut [u8]) -> ZipResult<()> {
+        use libdeflate_sys::*;
+
+        // Use the _ex() variant, which tells us how much input the stream actually used.
+        let mut read = 0;
+        let mut written = 0;
+        // SAFETY: The decompressor is freed below and never escapes,
+        // and the pointers and lengths come from live slices.
+        let result = unsafe {
+            let decompressor = libdeflate_alloc_decompressor();
+            if decompressor.is_null() {
+                return Err(ZipError::Io(io::Error::from(io::ErrorKind::OutOfMemory)));
+            }
+            let result = libdeflate_deflate_decompress_ex(
+                decompressor,
+                compressed.as_ptr().cast(),
+                compressed.len(),
+                out.as_mut_ptr().cast(),
+                out.len(),
+                &mut read,
+                &mut written,
+            );
+            libdeflate_free_decompressor(decompressor);
+            result
+        };
+        #[allow(non_upper_case_globals)]
+        match result {
+            libdeflate_result_LIBDEFLATE_SUCCESS if written != out.len() => Err(wrong_size()),
+            libdeflate_result_LIBDEFLATE_SUCCESS if read != compressed.len() => {
+                Err(ZipError::Io(trailing_data()))
+            }
+            libdeflate_result_LIBDEFLATE_SUCCESS => Ok(()),
+            libdeflate_result_LIBDEFLATE_INSUFFICIENT_SPACE => Err(wrong_size()),
+            // libdeflate doesn't distinguish truncated streams from other bad data.
+            _ => Err(corrupt()),
+        }
+    }
+}
+
+/// A `DeflateDecoder` that makes sure the stream uses all of its input
+///
+/// Garbage after the end of a Deflate stream is just as suspicious
+/// as a stream that decompresses to the wrong size.
+struct WholeStream<R> {
+    decoder: DeflateDecoder<R>,
+    compressed_size: u64,
+}
+
+impl<R: io::Read> io::Read for WholeStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.decoder.read(buf)?;
+        if count == 0 && !buf.is_empty() && self.decoder.total_in() != self.compressed_size {
+            return Err(trailing_data());
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,141 @@
+//! Opening archive files, by reading them or (unsafely) memory mapping them
+//!
+//! Reading a file into memory is always available.
+//! Memory mapping (with the `mmap` feature) is the way to read large archives,
+//! but `Mmap::map()` is `unsafe` for good reason:
+//! if some other process truncates the file while it's mapped,
+//! touching the missing pages kills us with `SIGBUS`.
+//! Nothing we can check beforehand prevents that
+//! (the file could be truncated right after we check it),
+//! so mapping stays `unsafe`, and callers promise to leave the file alone.
+
+use std::fs::File;
+#[cfg(feature = "mmap")]
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use log::*;
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+
+use crate::result::*;
+
+/// Files smaller than this are just read into memory.
+#[cfg(feature = "mmap")]
+const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
+/// An archive file opened by [`OwnedZipArchive::open()`]
+///
+/// [`ArchiveFile::open()`] always reads the file into a buffer.
+/// With the `mmap` feature, [`ArchiveFile::open_mapped()`] memory maps
+/// files of 1 MiB or more instead (and reads smaller ones),
+/// which is `unsafe`.
+/// Archives made from a mapped file check that it hasn't been truncated
+/// before each read, test, or parallel read starts.
+///
+/// [`OwnedZipArchive::open()`]: ../read/struct.OwnedZipArchive.html#method.open
+/// [`ArchiveFile::open_mapped()`]: #method.open_mapped
+pub struct ArchiveFile {
+    contents: Contents,
+}
+
+enum Contents {
+    Buffered(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped {
+        file: File,
+        mapping: Mmap,
+    },
+}
+
+impl ArchiveFile {
+    /// Opens the file at the given path, reading it into memory.
+    pub fn open<P: AsRef<Path>>(path: P) -> ZipResult<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        Self::read(path, file)
+    }
+
+    /// Opens the file at the given path, memory mapping it if it's 1 MiB or larger
+    /// (and reading it into memory otherwise).
+    ///
+    /// # Safety
+    ///
+    /// Like [`Mmap::map()`], this is only sound if the file isn't modified
+    /// (by this process or any other) while the `ArchiveFile`
+    /// or any reader made from it is alive.
+    /// In particular, reading from a mapping of a file that's since been truncated
+    /// kills the program with `SIGBUS`.
+    ///
+    /// [`Mmap::map()`]: https://docs.rs/memmap2/latest/memmap2/struct.Mmap.html#method.map
+    #[cfg(feature = "mmap")]
+    pub unsafe fn open_mapped<P: AsRef<Path>>(path: P) -> ZipResult<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if len < MMAP_THRESHOLD {
+            return Self::read(path, file);
+        }
+
+        debug!("Memory mapping {} ({} bytes)", path.display(), len);
+        // SAFETY: Our caller promises not to modify the file while it's mapped.
+        let mapping = unsafe { Mmap::map(&file)? };
+        Ok(Self {
+            contents: Contents::Mapped { file, mapping },
+        })
+    }
+
+    fn read(path: &Path, mut file: File) -> ZipResult<Self> {
+        let len = file.metadata()?.len();
+        debug!("Reading {} ({} bytes) into memory", path.display(), len);
+        let mut buffer = Vec::with_capacity(usize::try_from(len).unwrap_or(0));
+        file.read_to_end(&mut buffer)?;
+        Ok(Self {
+            contents: Contents::Buffered(buffer),
+        })
+    }
+
+    /// Returns true if the file was memory mapped (instead of read into memory).
+    pub fn is_mapped(&self) -> bool {
+        match self.contents {
+            Contents::Buffered(_) => false,
+            #[cfg(feature = "mmap")]
+            Contents::Mapped { .. } => true,
+        }
+    }
+
+    /// Fails if a mapped file's length changed since it was mapped,
+    /// in which case reading from the mapping could crash the program.
+    ///
+    /// Archives call this before reading from a mapped file,
+    /// but it only detects truncation that already happened;
+    /// it doesn't make reading from the mapping safe
+    /// (see [`ArchiveFile::open_mapped()`]).
+    ///
+    /// [`ArchiveFile::open_mapped()`]: #method.open_mapped
+    pub fn check_unchanged(&self) -> ZipResult<()> {
+        #[cfg(feature = "mmap")]
+        if let Contents::Mapped { file, mapping } = &self.contents {
+            let len = file.metadata()?.len();
+            if len != mapping.len() as u64 {
+                return Err(ZipError::Io(io::Error::other(format!(
+                    "Archive file changed size from {} to {} bytes while mapped",
+                    mapping.len(),
+                    len
+                ))));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsRef<[u8]> for ArchiveFile {
+    fn as_ref(&self) -> &[u8] {
+        match &self.contents {
+            Contents::Buffered(buffer) => buffer,
+            #[cfg(feature = "mmap")]
+            Contents::Mapped { mapping, .. } => mapping,
+        }
+    }
+}
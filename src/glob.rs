@@ -0,0 +1,326 @@
+//! Shell-style glob patterns for finding entries in a [`FileTree`]
+//!
+//! [`FileTree`]: ../read/trait.FileTree.html
+
+use camino::Utf8Path;
+
+use crate::insensitive::normal_components;
+use crate::read::{DirectoryEntry, TreeFilter, Visit};
+use crate::result::*;
+
+/// A glob pattern that matches paths in a [`FileTree`]
+///
+/// Patterns are matched against whole paths, component by component:
+///
+/// - `*` matches any run of characters in a component
+/// - `?` matches any single character
+/// - `[abc]`, `[a-z]` match one character from the given set or range,
+///   and `[!abc]` or `[^abc]` match one character _not_ in it
+/// - `**` (as a whole component) matches zero or more components
+/// - `\` escapes the next character
+///
+/// Pass one to [`FileTree::filter()`] to find matching entries.
+/// Directories that can't contain any matches are skipped entirely.
+///
+/// ```no_run
+/// # use std::fs;
+/// # use std::io;
+/// # use rayon::prelude::*;
+/// # use piz::*;
+/// # use piz::read::*;
+/// let bytes = fs::read("foo.jar")?;
+/// let archive = ZipArchive::new(&bytes)?;
+/// let tree = as_tree(archive.entries())?;
+/// let classes = Glob::new("com/acme/**/*.class")?;
+/// tree.filter(&classes)
+///     .map(DirectoryEntry::metadata)
+///     .filter(|entry| entry.is_file())
+///     .par_bridge()
+///     .try_for_each(|entry| {
+///         let mut reader = archive.read(entry)?;
+///         io::copy(&mut reader, &mut io::sink())?;
+///         Ok::<(), anyhow::Error>(())
+///     })?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`FileTree`]: ../read/trait.FileTree.html
+/// [`FileTree::filter()`]: ../read/trait.FileTree.html#tymethod.filter
+#[derive(Debug, Clone)]
+pub struct Glob {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// `**`, which matches any number of components
+    AnyComponents,
+    /// Anything else, which matches exactly one component
+    Component(Vec<Token>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(char),
+    AnyChar,
+    AnyRun,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Glob {
+    /// Parses a glob pattern.
+    ///
+    /// Fails with [`ZipError::InvalidPath`] if the pattern is malformed
+    /// (e.g., has an unclosed `[`).
+    pub fn new(pattern: &str) -> ZipResult<Self> {
+        let segments = pattern
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| parse_segment(s, pattern))
+            .collect::<ZipResult<_>>()?;
+        Ok(Self { segments })
+    }
+
+    /// Returns true if the given path matches the pattern.
+    pub fn matches<P: AsRef<Utf8Path>>(&self, path: P) -> bool {
+        self.states_after(path.as_ref())
+            .contains(&self.segments.len())
+    }
+
+    /// Runs the pattern over the path's components,
+    /// returning the positions in the pattern we could be at after them.
+    /// (An empty result means nothing at or under the path can match.)
+    fn states_after(&self, path: &Utf8Path) -> Vec<usize> {
+        let mut states = self.closure(vec![0]);
+        for component in normal_components(path) {
+            let mut next = Vec::new();
+            for &state in &states {
+                match self.segments.get(state) {
+                    Some(Segment::AnyComponents) => next.push(state),
+                    Some(Segment::Component(tokens)) if matches_component(tokens, component) => {
+                        next.push(state + 1)
+                    }
+                    _ => {}
+                }
+            }
+            states = self.closure(next);
+            if states.is_empty() {
+                break;
+            }
+        }
+        states
+    }
+
+    /// Since `**` can match zero components,
+    /// being before one means we're also after it.
+    fn closure(&self, mut states: Vec<usize>) -> Vec<usize> {
+        let mut i = 0;
+        while i < states.len() {
+            let state = states[i];
+            if self.segments.get(state) == Some(&Segment::AnyComponents) {
+                states.push(state + 1);
+            }
+            i += 1;
+        }
+        states.sort_unstable();
+        states.dedup();
+        states
+    }
+}
+
+impl<'a> TreeFilter<'a> for &Glob {
+    fn visit(&mut self, entry: &DirectoryEntry<'a>) -> Visit {
+        let states = self.states_after(&entry.metadata().path);
+        let matches = states.contains(&self.segments.len());
+        let could_match_children = states.iter().any(|&s| s < self.segments.len());
+        match (matches, could_match_children) {
+            (true, true) => Visit::Yield,
+            (true, false) => Visit::YieldAndPrune,
+            (false, true) => Visit::Skip,
+            (false, false) => Visit::Prune,
+        }
+    }
+}
+
+fn parse_segment(segment: &str, pattern: &str) -> ZipResult<Segment> {
+    if segment == "**" {
+        return Ok(Segment::AnyComponents);
+    }
+
+    let mut tokens = Vec::new();
+    let mut chars = segment.chars();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '*' => {
+                // a**b is just a*b.
+                if tokens.last() == Some(&Token::AnyRun) {
+                    continue;
+                }
+                Token::AnyRun
+            }
+            '?' => Token::AnyChar,
+            '\\' => Token::Literal(chars.next().unwrap_or('\\')),
+            '[' => parse_class(&mut chars, pattern)?,
+            c => Token::Literal(c),
+        };
+        tokens.push(token);
+    }
+    Ok(Segment::Component(tokens))
+}
+
+/// Parses a character class, just past its opening `[`.
+fn parse_class(chars: &mut std::str::Chars, pattern: &str) -> ZipResult<Token> {
+    let unclosed = || ZipError::InvalidPath(format!("Unclosed [ in glob {pattern}"));
+
+    let mut negated = false;
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let c = chars.next().ok_or_else(unclosed)?;
+        match c {
+            '!' | '^' if first && !negated => {
+                negated = true;
+                continue;
+            }
+            // Like shells, allow []] and [!]] to match a ].
+            ']' if !first => break,
+            _ => {}
+        }
+        first = false;
+
+        let start = if c == '\\' {
+            chars.next().ok_or_else(unclosed)?
+        } else {
+            c
+        };
+        // Peek for a range, but a trailing - (as in [a-]) is literal.
+        let mut lookahead = chars.clone();
+        if lookahead.next() == Some('-') {
+            match lookahead.next() {
+                Some(']') | None => {}
+                Some(end) => {
+                    *chars = lookahead;
+                    ranges.push((start, end));
+                    continue;
+                }
+            }
+        }
+        ranges.push((start, start));
+    }
+    Ok(Token::Class { negated, ranges })
+}
+
+/// Matches a single path component against a segment's tokens.
+///
+/// This is the usual wildcard algorithm: on a mismatch,
+/// back up to the last `*` and let it eat one more character.
+fn matches_component(tokens: &[Token], name: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    let (mut t, mut n) = (0, 0);
+    // Where to resume after the last * we saw: (token after it, name position)
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        let advanced = match tokens.get(t) {
+            Some(Token::AnyRun) => {
+                backtrack = Some((t + 1, n));
+                t += 1;
+                continue;
+            }
+            Some(Token::AnyChar) => true,
+            Some(Token::Literal(l)) => *l == name[n],
+            Some(Token::Class { negated, ranges }) => {
+                let c = name[n];
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }
+            None => false,
+        };
+        if advanced {
+            t += 1;
+            n += 1;
+        } else if let Some((bt, bn)) = backtrack {
+            t = bt;
+            n = bn + 1;
+            backtrack = Some((bt, bn + 1));
+        } else {
+            return false;
+        }
+    }
+    tokens[t..].iter().all(|token| *token == Token::AnyRun)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::read::test::metadata;
+    use crate::read::{as_tree, FileTree};
+
+    #[test]
+    fn patterns() -> ZipResult<()> {
+        let glob = Glob::new("com/acme/**/*.class")?;
+        assert!(glob.matches("com/acme/Foo.class"));
+        assert!(glob.matches("com/acme/deep/er/Foo.class"));
+        assert!(!glob.matches("com/acme/Foo.java"));
+        assert!(!glob.matches("org/acme/Foo.class"));
+
+        let glob = Glob::new("data/file?.[a-c!]x[!0-9]")?;
+        assert!(glob.matches("data/file1.bxy"));
+        assert!(glob.matches("data/fileZ.!xy"));
+        assert!(!glob.matches("data/file12.bxy"));
+        assert!(!glob.matches("data/file1.dxy"));
+        assert!(!glob.matches("data/file1.bx7"));
+
+        assert!(Glob::new("**")?.matches("anything/at/all"));
+        assert!(Glob::new("a*b*c")?.matches("aXbYbZc"));
+        assert!(!Glob::new("a*b*c")?.matches("aXbYbZ"));
+        assert!(Glob::new("\\*")?.matches("*"));
+        assert!(!Glob::new("\\*")?.matches("x"));
+        assert!(matches!(Glob::new("[abc"), Err(ZipError::InvalidPath(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn pruning() -> ZipResult<()> {
+        let entries = vec![
+            metadata("com/", 0),
+            metadata("com/acme/", 1),
+            metadata("com/acme/A.class", 2),
+            metadata("com/acme/util/", 3),
+            metadata("com/acme/util/B.class", 4),
+            metadata("com/other/", 5),
+            metadata("com/other/C.class", 6),
+            metadata("README", 7),
+        ];
+        let tree = as_tree(&entries)?;
+
+        let glob = Glob::new("com/acme/**/*.class")?;
+        let found: Vec<usize> = tree
+            .filter(&glob)
+            .map(|e| e.metadata().header_offset)
+            .collect();
+        assert_eq!(found, [2, 4]);
+
+        // Predicates can prune too.
+        let mut visited = Vec::new();
+        let big: Vec<usize> = tree
+            .filter(|e: &DirectoryEntry| {
+                let metadata = e.metadata();
+                visited.push(metadata.header_offset);
+                if metadata.path.starts_with("com/other") {
+                    Visit::Prune
+                } else if metadata.is_file() {
+                    Visit::Yield
+                } else {
+                    Visit::Skip
+                }
+            })
+            .map(|e| e.metadata().header_offset)
+            .collect();
+        assert_eq!(big, [7, 2, 4]);
+        assert!(!visited.contains(&6));
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Case-insensitive, Unicode-normalized path lookups
+//!
+//! ZIP archives store paths as exact byte strings,
+//! but users on macOS and Windows are used to file systems that ignore case,
+//! and macOS in particular likes to decompose accented characters (NFD)
+//! where most everything else composes them (NFC).
+//! So `Café.txt` typed on one machine might not match `café.txt` stored
+//! by another, byte for byte.
+
+use std::collections::HashMap;
+
+use camino::{Utf8Component, Utf8Path};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::read::{DirectoryContents, FileMetadata, FileTree};
+use crate::result::*;
+
+/// Folds a path into the key used to compare it case-insensitively:
+/// lowercase, NFC-normalized, with components joined by `/`.
+///
+/// Non-normal components (`/`, `.`, etc.) are skipped;
+/// callers are expected to reject or warn about those themselves.
+pub(crate) fn fold_path(path: &Utf8Path) -> String {
+    let mut folded = String::with_capacity(path.as_str().len());
+    for component in normal_components(path) {
+        if !folded.is_empty() {
+            folded.push('/');
+        }
+        folded.extend(component.chars().flat_map(char::to_lowercase).nfc());
+    }
+    folded
+}
+
+/// Joins a path's normal components with `/`, dropping trailing slashes and the like,
+/// so that paths to the same place in the tree compare equal.
+pub(crate) fn exact_path(path: &Utf8Path) -> String {
+    normal_components(path).collect::<Vec<_>>().join("/")
+}
+
+pub(crate) fn normal_components(path: &Utf8Path) -> impl Iterator<Item = &str> {
+    path.components().filter_map(|c| match c {
+        Utf8Component::Normal(n) => Some(n),
+        _ => None,
+    })
+}
+
+/// An index of a [`FileTree`] that looks up paths case-insensitively
+/// and regardless of Unicode normalization (NFC vs. NFD).
+///
+/// ```no_run
+/// # use std::fs;
+/// # use piz::*;
+/// # use piz::read::*;
+/// let bytes = fs::read("foo.zip")?;
+/// let archive = ZipArchive::new(&bytes)?;
+/// let tree = as_tree(archive.entries())?;
+/// let index = CaseInsensitiveIndex::new(&tree);
+/// let metadata = index.lookup("SOME/Specific/FILE")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`FileTree`]: trait.FileTree.html
+#[derive(Debug)]
+pub struct CaseInsensitiveIndex<'a> {
+    paths: HashMap<String, Vec<&'a FileMetadata<'a>>>,
+}
+
+impl<'a> CaseInsensitiveIndex<'a> {
+    /// Indexes every file and directory in the given tree.
+    pub fn new(tree: &DirectoryContents<'a>) -> Self {
+        let mut paths: HashMap<String, Vec<&'a FileMetadata<'a>>> = HashMap::new();
+        for entry in tree.traverse() {
+            let metadata = entry.metadata();
+            paths
+                .entry(fold_path(&metadata.path))
+                .or_default()
+                .push(metadata);
+        }
+        Self { paths }
+    }
+
+    /// Looks up a file or directory by its path, ignoring case and normalization.
+    ///
+    /// If several entries match (say, `README` and `readme`),
+    /// an exact match is preferred.
+    /// Failing that, a [`ZipError::Hierarchy`] error is returned,
+    /// since there's no good way to pick one.
+    pub fn lookup<P: AsRef<Utf8Path>>(&self, path: P) -> ZipResult<&'a FileMetadata<'a>> {
+        let path = path.as_ref();
+        match self.lookup_all(path)? {
+            [only] => Ok(*only),
+            candidates => {
+                let exact = exact_path(path);
+                candidates
+                    .iter()
+                    .find(|c| exact_path(&c.path) == exact)
+                    .copied()
+                    .ok_or_else(|| {
+                        ZipError::Hierarchy(format!(
+                            "{path} matches {} entries that differ only by case or normalization",
+                            candidates.len()
+                        ))
+                    })
+            }
+        }
+    }
+
+    /// Returns every file or directory that matches the given path,
+    /// ignoring case and normalization.
+    pub fn lookup_all<P: AsRef<Utf8Path>>(&self, path: P) -> ZipResult<&[&'a FileMetadata<'a>]> {
+        let path = path.as_ref();
+        // Like FileTree::lookup(), revolt over weird stuff in user-provided paths.
+        if let Some(weird) = path
+            .components()
+            .find(|c| !matches!(c, Utf8Component::Normal(_)))
+        {
+            return Err(ZipError::InvalidPath(format!(
+                "{weird} found in path {path}"
+            )));
+        }
+        self.paths
+            .get(&fold_path(path))
+            .map(Vec::as_slice)
+            .ok_or_else(|| ZipError::NoSuchFile(path.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::read::test::metadata;
+    use crate::read::{as_tree, as_tree_with, TreeOptions};
+
+    #[test]
+    fn folding() {
+        assert_eq!(
+            fold_path(Utf8Path::new("Dir/CAFE\u{301}.txt")),
+            "dir/caf\u{e9}.txt"
+        );
+        assert_eq!(fold_path(Utf8Path::new("dir/")), "dir");
+    }
+
+    #[test]
+    fn insensitive_lookups() -> ZipResult<()> {
+        let entries = vec![
+            metadata("Docs/", 0),
+            metadata("Docs/caf\u{e9}.txt", 1),
+            metadata("Docs/README", 2),
+            metadata("Docs/readme", 3),
+        ];
+        let tree = as_tree(&entries)?;
+        let index = CaseInsensitiveIndex::new(&tree);
+
+        assert_eq!(index.lookup("docs")?.header_offset, 0);
+        assert_eq!(index.lookup("DOCS/CAFE\u{301}.TXT")?.header_offset, 1);
+        assert_eq!(index.lookup("Docs/readme")?.header_offset, 3);
+        assert_eq!(index.lookup_all("docs/Readme")?.len(), 2);
+        assert!(matches!(
+            index.lookup("docs/Readme"),
+            Err(ZipError::Hierarchy(_))
+        ));
+        assert!(matches!(
+            index.lookup("docs/nope"),
+            Err(ZipError::NoSuchFile(_))
+        ));
+        assert!(matches!(
+            index.lookup("../docs"),
+            Err(ZipError::InvalidPath(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn collisions() {
+        let entries = vec![metadata("caf\u{e9}", 0), metadata("CAFE\u{301}", 1)];
+        assert!(as_tree(&entries).is_ok());
+        let options = TreeOptions::new().reject_case_collisions(true);
+        assert!(matches!(
+            as_tree_with(&entries, &options),
+            Err(ZipError::Hierarchy(_))
+        ));
+    }
+}
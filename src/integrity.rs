@@ -0,0 +1,80 @@
+//! Testing an archive's integrity, like `unzip -t`
+//!
+//! Reading an archive stops at the first bad file,
+//! but when vetting an archive, we'd like to hear about all of them.
+
+use std::io;
+
+use log::*;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::read::*;
+use crate::result::*;
+
+/// The results of [`ZipArchive::test()`]
+///
+/// [`ZipArchive::test()`]: struct.ZipArchive.html#method.test
+#[derive(Debug)]
+pub struct TestReport<'a> {
+    /// The number of entries tested
+    pub entries_tested: usize,
+    /// Every entry that failed, in archive order
+    pub failures: Vec<TestFailure<'a>>,
+}
+
+impl TestReport<'_> {
+    /// Returns true if every entry passed.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// An entry that failed [`ZipArchive::test()`], and why
+///
+/// [`ZipArchive::test()`]: struct.ZipArchive.html#method.test
+#[derive(Debug)]
+pub struct TestFailure<'a> {
+    pub entry: &'a FileMetadata<'a>,
+    pub error: ZipError,
+}
+
+/// Tests each of the archive's entries, in parallel if we can,
+/// drawing from the same budget as reads.
+pub(crate) fn test_entries<'a>(archive: &'a ZipArchive) -> TestReport<'a> {
+    let test = |entry: &'a FileMetadata<'a>| {
+        test_entry(archive, entry)
+            .map_err(in_entry(archive.archive_offset(), entry))
+            .err()
+            .map(|error| TestFailure { entry, error })
+    };
+
+    let entries = archive.entries();
+    #[cfg(feature = "parallel")]
+    let failures: Vec<_> = entries.par_iter().filter_map(test).collect();
+    #[cfg(not(feature = "parallel"))]
+    let failures: Vec<_> = entries.iter().filter_map(test).collect();
+
+    for failure in &failures {
+        warn!("{} failed: {}", failure.entry.path, failure.error);
+    }
+    TestReport {
+        entries_tested: entries.len(),
+        failures,
+    }
+}
+
+fn test_entry(archive: &ZipArchive, entry: &FileMetadata) -> ZipResult<()> {
+    archive.check_file()?;
+    let mapping = archive.mapping();
+    // Always check the local header, even without the check-local-metadata feature.
+    let range = data_range_with(mapping, entry, true)?;
+    let data = mapping.get(range).ok_or(ZipError::InvalidArchive(
+        "File data runs past the end of the archive",
+    ))?;
+    archive.budget().spend(entry)?;
+    // Readers check the size and CRC when they hit the end.
+    let mut reader = make_reader(entry, data, archive.decoders())?;
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(())
+}
@@ -68,17 +68,43 @@
 //!
 //! Users can either read the entire archive into memory, or, for larger archives,
 //! [memory-map](https://docs.rs/memmap/0.7.0/memmap/struct.Mmap.html) the file.
+//! ([`OwnedZipArchive::open()`] reads it into memory for you, whatever its size.
+//! With the `mmap` feature, `ArchiveFile::open_mapped()` maps files of 1 MiB or more
+//! and reads smaller ones, which is as `unsafe` as mapping them yourself.)
 //! (On 64-bit systems, this allows us to treat archives as a contiguous byte range
 //! even if the file is _much_ larger than physical RAM. 32-bit systems are limited
 //! by address space to archives under 4 GB, but piz _should_ be well-behaved
 //! if the archive is small enough.)
+//!
+//! DEFLATE goes through [flate2](https://crates.io/crates/flate2),
+//! which uses the pure Rust miniz_oxide by default.
+//! The `deflate-zlib-rs` and `deflate-zlib-ng` features switch it to those backends,
+//! and the `libdeflate` feature uses libdeflate to decompress whole files at once
+//! (e.g., with [`ZipArchive::read_to_vec()`]).
+//! The backend is picked at compile time with these features;
+//! there's no runtime switch, and [`read::Decoders`] can't replace it for Deflate
+//! (they only handle compression methods piz doesn't know).
 
 pub mod read;
 pub mod result;
 
 pub use read::CompressionMethod;
+pub use read::OwnedZipArchive;
 pub use read::ZipArchive;
 
 mod arch;
 mod crc_reader;
+mod decoders;
+mod deflate;
+mod file;
+mod glob;
+mod insensitive;
+mod integrity;
+mod limits;
+mod owned;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod recover;
+mod sanitize;
+mod seek;
 mod spec;
@@ -0,0 +1,319 @@
+//! Defenses against zip bombs
+//!
+//! The classic (non-recursive) zip bomb points thousands of central directory
+//! entries at the same highly-compressed data, so a few megabytes of archive
+//! "contain" terabytes of files. Others just declare absurd sizes or ratios.
+//! [`Limits`] rejects archives like these before we decompress anything,
+//! and keeps track of how much its archive's readers have handed out.
+//!
+//! Even opening an archive means trusting its central directory's counts and sizes,
+//! so [`OpenOptions`] bounds those before we allocate or parse anything.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::*;
+
+use crate::read::FileMetadata;
+use crate::result::*;
+use crate::spec;
+
+/// Limits on what an archive may contain and how much it may decompress to
+///
+/// Apply them with [`ZipArchive::with_limits()`].
+/// The default is no limits at all, but overlapping entries are always rejected.
+///
+/// ```no_run
+/// # use std::fs;
+/// # use piz::*;
+/// # use piz::read::*;
+/// let bytes = fs::read("upload.zip")?;
+/// let archive = ZipArchive::new(&bytes)?.with_limits(
+///     Limits::new()
+///         .max_entries(10_000)
+///         .max_total_size(1024 * 1024 * 1024)
+///         .max_ratio(100),
+/// )?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`ZipArchive::with_limits()`]: struct.ZipArchive.html#method.with_limits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    max_entries: Option<usize>,
+    max_total_size: Option<u64>,
+    max_ratio: Option<u64>,
+}
+
+impl Limits {
+    /// Creates a set of limits that allows anything (except overlapping entries).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows at most the given number of entries.
+    pub fn max_entries(mut self, entries: usize) -> Self {
+        self.max_entries = Some(entries);
+        self
+    }
+
+    /// Allows at most the given number of uncompressed bytes,
+    /// both in the sizes entries declare, and in what the archive's readers produce.
+    pub fn max_total_size(mut self, bytes: u64) -> Self {
+        self.max_total_size = Some(bytes);
+        self
+    }
+
+    /// Allows each entry to be at most `ratio` times bigger than its compressed data.
+    ///
+    /// Deflate tops out around 1032:1, so anything past that is certainly fishy.
+    pub fn max_ratio(mut self, ratio: u64) -> Self {
+        self.max_ratio = Some(ratio);
+        self
+    }
+
+    /// Checks the given entry's size and compression ratio.
+    fn check_entry(&self, entry: &FileMetadata) -> ZipResult<()> {
+        if let Some(max_ratio) = self.max_ratio {
+            let size = entry.size as u64;
+            if size > (entry.compressed_size as u64).saturating_mul(max_ratio) {
+                return Err(ZipError::LimitExceeded(format!(
+                    "{} expands from {} to {} bytes (more than {}:1)",
+                    entry.path, entry.compressed_size, entry.size, max_ratio
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the given entries against these limits,
+    /// and makes sure none of them overlap.
+    pub(crate) fn check_entries(&self, mapping: &[u8], entries: &[FileMetadata]) -> ZipResult<()> {
+        if let Some(max_entries) = self.max_entries {
+            if entries.len() > max_entries {
+                return Err(ZipError::LimitExceeded(format!(
+                    "{} entries (more than {})",
+                    entries.len(),
+                    max_entries
+                )));
+            }
+        }
+        let mut total_size = 0u64;
+        for entry in entries {
+            self.check_entry(entry)?;
+            total_size = total_size.saturating_add(entry.size as u64);
+        }
+        if let Some(max_total_size) = self.max_total_size {
+            if total_size > max_total_size {
+                return Err(ZipError::LimitExceeded(format!(
+                    "Entries total {} bytes (more than {})",
+                    total_size, max_total_size
+                )));
+            }
+        }
+        check_overlaps(mapping, entries)
+    }
+}
+
+/// Options for opening an archive, bounding the resources its metadata can claim
+///
+/// Use them with [`ZipArchive::with_options()`].
+/// The default is no limits, though piz never preallocates for more entries
+/// than the central directory has room for.
+/// To bound the entry count with the same number as your [`Limits`],
+/// start from `OpenOptions::from(limits)`.
+///
+/// ```no_run
+/// # use std::fs;
+/// # use piz::*;
+/// # use piz::read::*;
+/// let bytes = fs::read("upload.zip")?;
+/// let options = OpenOptions::new()
+///     .max_entries(10_000)
+///     .max_central_directory_size(16 * 1024 * 1024)
+///     .max_path_length(1024)
+///     .max_extra_field_size(1024);
+/// let (archive, _offset) = ZipArchive::with_options(&bytes, &options)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`ZipArchive::with_options()`]: struct.ZipArchive.html#method.with_options
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    max_entries: Option<usize>,
+    max_central_directory_size: Option<u64>,
+    max_path_length: Option<usize>,
+    max_extra_field_size: Option<usize>,
+}
+
+impl OpenOptions {
+    /// Creates the default options, which limit nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows at most the given number of entries,
+    /// checked against the count in the End Of Central Directory Record
+    /// before reading any of them.
+    pub fn max_entries(mut self, entries: usize) -> Self {
+        self.max_entries = Some(entries);
+        self
+    }
+
+    /// Allows a central directory of at most the given number of bytes.
+    pub fn max_central_directory_size(mut self, bytes: u64) -> Self {
+        self.max_central_directory_size = Some(bytes);
+        self
+    }
+
+    /// Allows paths of at most the given number of bytes.
+    pub fn max_path_length(mut self, bytes: usize) -> Self {
+        self.max_path_length = Some(bytes);
+        self
+    }
+
+    /// Allows extra fields of at most the given number of bytes per entry.
+    pub fn max_extra_field_size(mut self, bytes: usize) -> Self {
+        self.max_extra_field_size = Some(bytes);
+        self
+    }
+
+    /// Checks the central directory's declared size and entry count.
+    pub(crate) fn check_directory(&self, entries: u64, size: u64) -> ZipResult<()> {
+        if let Some(max_entries) = self.max_entries {
+            if entries > max_entries as u64 {
+                return Err(ZipError::LimitExceeded(format!(
+                    "{} entries (more than {})",
+                    entries, max_entries
+                )));
+            }
+        }
+        if let Some(max_size) = self.max_central_directory_size {
+            if size > max_size {
+                return Err(ZipError::LimitExceeded(format!(
+                    "{} byte central directory (more than {})",
+                    size, max_size
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a central directory entry's path and extra field.
+    pub(crate) fn check_entry(&self, entry: &spec::CentralDirectoryEntry) -> ZipResult<()> {
+        if let Some(max_path_length) = self.max_path_length {
+            if entry.path.len() > max_path_length {
+                return Err(ZipError::LimitExceeded(format!(
+                    "{} byte path (more than {})",
+                    entry.path.len(),
+                    max_path_length
+                )));
+            }
+        }
+        if let Some(max_extra_field_size) = self.max_extra_field_size {
+            if entry.extra_field.len() > max_extra_field_size {
+                return Err(ZipError::LimitExceeded(format!(
+                    "{} byte extra field (more than {})",
+                    entry.extra_field.len(),
+                    max_extra_field_size
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Carries over the limit on entries, checking it before reading any of them.
+impl From<Limits> for OpenOptions {
+    fn from(limits: Limits) -> Self {
+        Self {
+            max_entries: limits.max_entries,
+            ..Self::default()
+        }
+    }
+}
+
+/// Makes sure no two entries share any data,
+/// from the start of their local file header to the end of their compressed data.
+pub(crate) fn check_overlaps(mapping: &[u8], entries: &[FileMetadata]) -> ZipResult<()> {
+    let mut extents = entries
+        .iter()
+        .map(|entry| Ok((extent(mapping, entry)?, entry)))
+        .collect::<ZipResult<Vec<_>>>()?;
+    extents.sort_unstable_by_key(|(range, _)| range.start);
+
+    for pair in extents.windows(2) {
+        let ((previous, previous_entry), (next, next_entry)) = (&pair[0], &pair[1]);
+        if next.start < previous.end {
+            warn!(
+                "{} (at {}..{}) overlaps {} (at {}..{})",
+                next_entry.path,
+                next.start,
+                next.end,
+                previous_entry.path,
+                previous.start,
+                previous.end
+            );
+            return Err(ZipError::InvalidArchive(
+                "Entries overlap each other's data",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns where the given entry's local header and data sit in the mapping.
+fn extent(mapping: &[u8], entry: &FileMetadata) -> ZipResult<std::ops::Range<usize>> {
+    let past_end = || ZipError::InvalidArchive("File data runs past the end of the archive");
+
+    let mut header = mapping.get(entry.header_offset..).ok_or_else(past_end)?;
+    let before = header.len();
+    spec::LocalFileHeader::parse_and_consume(&mut header)?;
+    let end = (entry.header_offset + before - header.len())
+        .checked_add(entry.compressed_size)
+        .filter(|end| *end <= mapping.len())
+        .ok_or_else(past_end)?;
+    Ok(entry.header_offset..end)
+}
+
+/// How many bytes an archive's readers have handed out, against its [`Limits`]
+#[derive(Debug, Default)]
+pub(crate) struct Budget {
+    limits: Limits,
+    spent: AtomicU64,
+}
+
+impl Budget {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            spent: AtomicU64::new(0),
+        }
+    }
+
+    /// Reserves the given entry's (declared) size before reading it.
+    ///
+    /// Readers fail as soon as they pass an entry's declared size,
+    /// so a file can't decompress to more than we reserve here.
+    pub(crate) fn spend(&self, entry: &FileMetadata) -> ZipResult<()> {
+        self.limits.check_entry(entry)?;
+        let Some(max_total_size) = self.limits.max_total_size else {
+            return Ok(());
+        };
+        let size = entry.size as u64;
+        self.spent
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |spent| {
+                spent
+                    .checked_add(size)
+                    .filter(|total| *total <= max_total_size)
+            })
+            .map(|_| ())
+            .map_err(|spent| {
+                ZipError::LimitExceeded(format!(
+                    "Reading {} would make {} bytes read (more than {})",
+                    entry.path,
+                    spent.saturating_add(size),
+                    max_total_size
+                ))
+            })
+    }
+}
@@ -0,0 +1,386 @@
+//! An archive that owns its data, for when borrowing is a hassle.
+//!
+//! [`ZipArchive`] borrows the bytes it reads from,
+//! and the tree from [`as_tree()`] borrows the archive's entries.
+//! That's great for a quick `main()`, but makes it awkward to stash an opened archive
+//! in a struct, a cache, or an `Arc` shared between threads.
+//!
+//! [`ZipArchive`]: ../read/struct.ZipArchive.html
+//! [`as_tree()`]: ../read/fn.as_tree.html
+
+use std::any::TypeId;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use self_cell::self_cell;
+
+use crate::read::*;
+use crate::result::*;
+
+/// The archive's data, whatever its type
+struct Data {
+    bytes: Box<dyn AsRef<[u8]> + Send + Sync>,
+    /// The same data, if it's an [`ArchiveFile`] (which might be mapped)
+    file: Option<Arc<ArchiveFile>>,
+}
+
+impl Data {
+    /// Erases the type of the archive's data, since self-referential structs can't be generic.
+    fn new<T: AsRef<[u8]> + Send + Sync + ?Sized + 'static>(data: &Arc<T>) -> Self {
+        let file = (TypeId::of::<T>() == TypeId::of::<ArchiveFile>()).then(|| {
+            let raw = Arc::into_raw(data.clone()).cast::<ArchiveFile>();
+            // SAFETY: T is ArchiveFile, so this is the same Arc.
+            unsafe { Arc::from_raw(raw) }
+        });
+        let len = (**data).as_ref().len();
+        Self {
+            bytes: Box::new(SharedSlice {
+                data: data.clone(),
+                range: 0..len,
+            }),
+            file,
+        }
+    }
+
+    /// Returns the data as a byte slice.
+    fn bytes(&self) -> &[u8] {
+        (*self.bytes).as_ref()
+    }
+
+    /// Has the archive check the file it came from before each read,
+    /// if that's a mapped file that could get truncated out from under us.
+    fn watch<'a>(&'a self, mut archive: ZipArchive<'a>) -> ZipArchive<'a> {
+        if let Some(file) = self.file.as_deref().filter(|f| f.is_mapped()) {
+            archive.own_paths();
+            archive.set_file(file);
+        }
+        archive
+    }
+}
+
+self_cell!(
+    /// The archive, borrowing its data
+    struct ArchiveCell {
+        owner: Data,
+
+        #[covariant]
+        dependent: ZipArchive,
+    }
+);
+
+self_cell!(
+    /// The archive, along with the tree that borrows its entries
+    struct ArchiveTree {
+        owner: ArchiveCell,
+
+        #[covariant]
+        dependent: DirectoryContents,
+    }
+);
+
+impl ArchiveTree {
+    /// Organizes the archive's entries into a tree.
+    fn build(archive: ArchiveCell, options: &TreeOptions) -> ZipResult<Self> {
+        Self::try_new(archive, |archive| {
+            as_tree_with(archive.borrow_dependent().entries(), options)
+        })
+    }
+}
+
+/// A ZIP archive that owns (a shared reference to) its data
+/// and the [`FileTree`] of its entries.
+///
+/// The data can be anything that can be viewed as a byte slice:
+/// a `Vec<u8>`, an `[u8]`, a memory map, etc.
+/// Everything besides opening goes through the borrowed [`ZipArchive`]
+/// from [`archive()`](Self::archive),
+/// except for [`read()`](Self::read) and [`read_seekable()`](Self::read_seekable),
+/// whose readers hold a reference to the data as well, so they're `'static`
+/// and can outlive the archive that made them.
+///
+/// ```no_run
+/// # use std::fs::File;
+/// # use std::io;
+/// # use std::sync::Arc;
+/// # use memmap2::Mmap;
+/// # use piz::*;
+/// # use piz::read::*;
+/// let zip_file = File::open("foo.zip")?;
+/// let mapping = unsafe { Mmap::map(&zip_file)? };
+/// let archive = Arc::new(OwnedZipArchive::new(Arc::new(mapping))?);
+///
+/// let metadata = archive.tree().lookup("some/specific/file")?;
+/// let mut reader = archive.read(metadata)?;
+/// std::thread::spawn(move || io::copy(&mut reader, &mut io::sink()));
+///
+/// let contents = archive.archive().read_to_vec(archive.tree().lookup("README")?)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`FileTree`]: ../read/trait.FileTree.html
+/// [`ZipArchive`]: ../read/struct.ZipArchive.html
+pub struct OwnedZipArchive<T: ?Sized> {
+    data: Arc<T>,
+    tree: ArchiveTree,
+    /// How `tree` was built, so we can build it again
+    tree_options: TreeOptions,
+}
+
+impl<T> OwnedZipArchive<T>
+where
+    T: AsRef<[u8]> + Send + Sync + ?Sized + 'static,
+{
+    /// Reads a ZIP archive from the given data and organizes its entries
+    /// into a tree, like [`ZipArchive::new()`] followed by [`as_tree()`].
+    ///
+    /// [`ZipArchive::new()`]: ../read/struct.ZipArchive.html#method.new
+    /// [`as_tree()`]: ../read/fn.as_tree.html
+    pub fn new(data: Arc<T>) -> ZipResult<Self> {
+        let (new_archive, archive_offset) = Self::with_prepended_data(data)?;
+        if archive_offset != 0 {
+            return Err(ZipError::PrependedWithUnknownBytes(archive_offset));
+        }
+        Ok(new_archive)
+    }
+
+    /// Like `OwnedZipArchive::new()`, but allows arbitrary data to prepend the archive.
+    /// Returns the archive and the number of bytes prepended to it.
+    ///
+    /// See [`ZipArchive::with_prepended_data()`].
+    ///
+    /// [`ZipArchive::with_prepended_data()`]: ../read/struct.ZipArchive.html#method.with_prepended_data
+    pub fn with_prepended_data(data: Arc<T>) -> ZipResult<(Self, usize)> {
+        Self::with_tree_options(data, &TreeOptions::default())
+    }
+
+    /// Like `OwnedZipArchive::with_prepended_data()`,
+    /// but builds the tree with the given options, like [`as_tree_with()`].
+    ///
+    /// [`as_tree_with()`]: ../read/fn.as_tree_with.html
+    pub fn with_tree_options(data: Arc<T>, options: &TreeOptions) -> ZipResult<(Self, usize)> {
+        Self::with_options(data, &OpenOptions::default(), options)
+    }
+
+    /// Like `OwnedZipArchive::with_tree_options()`,
+    /// but bounds the archive's metadata with the given [`OpenOptions`],
+    /// like [`ZipArchive::with_options()`].
+    ///
+    /// [`ZipArchive::with_options()`]: ../read/struct.ZipArchive.html#method.with_options
+    pub fn with_options(
+        data: Arc<T>,
+        open_options: &OpenOptions,
+        tree_options: &TreeOptions,
+    ) -> ZipResult<(Self, usize)> {
+        let archive = ArchiveCell::try_new(Data::new(&data), |data| {
+            let (archive, _) = ZipArchive::with_options(data.bytes(), open_options)?;
+            ZipResult::Ok(data.watch(archive))
+        })?;
+        let archive_offset = archive.borrow_dependent().archive_offset();
+        let new_archive = Self {
+            data,
+            tree: ArchiveTree::build(archive, tree_options)?,
+            tree_options: tree_options.clone(),
+        };
+        Ok((new_archive, archive_offset))
+    }
+
+    /// Salvages whatever entries it can from a damaged archive,
+    /// organizing them into a tree with the given options.
+    ///
+    /// See [`ZipArchive::recover()`].
+    /// (Salvaged archives often have several versions of a file,
+    /// so consider [`DuplicatePolicy::LastWins`].)
+    ///
+    /// [`ZipArchive::recover()`]: ../read/struct.ZipArchive.html#method.recover
+    /// [`DuplicatePolicy::LastWins`]: ../read/enum.DuplicatePolicy.html#variant.LastWins
+    pub fn recover(data: Arc<T>, options: &TreeOptions) -> ZipResult<(Self, RecoveryReport)> {
+        let mut report = None;
+        let archive = ArchiveCell::new(Data::new(&data), |data| {
+            let (archive, recovered) = ZipArchive::recover(data.bytes());
+            report = Some(recovered);
+            data.watch(archive)
+        });
+        let new_archive = Self {
+            data,
+            tree: ArchiveTree::build(archive, options)?,
+            tree_options: options.clone(),
+        };
+        Ok((new_archive, report.unwrap()))
+    }
+
+    /// Decodes files with compression methods piz doesn't support itself
+    /// using the given decoders.
+    ///
+    /// See [`ZipArchive::with_decoders()`].
+    ///
+    /// [`ZipArchive::with_decoders()`]: ../read/struct.ZipArchive.html#method.with_decoders
+    pub fn with_decoders(self, decoders: Decoders) -> Self {
+        self.with_archive(|archive| {
+            archive.set_decoders(decoders);
+            Ok(())
+        })
+        // The tree was built from these same entries before.
+        .expect("Couldn't rebuild the archive's tree")
+    }
+
+    /// Checks the archive's entries against the given limits,
+    /// and enforces them on reads from here on out.
+    ///
+    /// See [`ZipArchive::with_limits()`].
+    ///
+    /// [`ZipArchive::with_limits()`]: ../read/struct.ZipArchive.html#method.with_limits
+    pub fn with_limits(self, limits: Limits) -> ZipResult<Self> {
+        self.with_archive(|archive| archive.set_limits(limits))
+    }
+
+    /// Returns the archive, borrowing this one's data.
+    ///
+    /// Use it for everything besides [`read()`](Self::read)
+    /// and [`read_seekable()`](Self::read_seekable):
+    /// reading files into buffers, testing the archive, reading in parallel, and so on.
+    pub fn archive(&self) -> &ZipArchive<'_> {
+        self.tree.borrow_owner().borrow_dependent()
+    }
+
+    /// Returns the tree of files and directories in the archive.
+    pub fn tree(&self) -> &DirectoryContents<'_> {
+        self.tree.borrow_dependent()
+    }
+
+    /// Returns the data the archive was read from.
+    pub fn data(&self) -> &Arc<T> {
+        &self.data
+    }
+
+    /// Reads the given file from the ZIP archive.
+    ///
+    /// Like [`ZipArchive::read()`], but the returned reader shares ownership
+    /// of the archive's data, so it isn't tied to the archive's lifetime.
+    ///
+    /// [`ZipArchive::read()`]: ../read/struct.ZipArchive.html#method.read
+    pub fn read(&self, metadata: &FileMetadata) -> ZipResult<Box<dyn io::Read + Send + 'static>> {
+        let archive = self.archive();
+        let read = || {
+            let file_data = self.shared_data(metadata)?;
+            archive.budget().spend(metadata)?;
+            make_reader(metadata, io::Cursor::new(file_data), archive.decoders())
+        };
+        read().map_err(in_entry(archive.archive_offset(), metadata))
+    }
+
+    /// Reads the given file from the ZIP archive with a reader that can [`Seek`].
+    ///
+    /// Like [`ZipArchive::read_seekable()`], but the returned reader
+    /// shares ownership of the archive's data.
+    ///
+    /// [`Seek`]: std::io::Seek
+    /// [`ZipArchive::read_seekable()`]: ../read/struct.ZipArchive.html#method.read_seekable
+    pub fn read_seekable(&self, metadata: &FileMetadata) -> ZipResult<SeekableReader<'static>> {
+        let archive = self.archive();
+        let read = || {
+            let file_data = self.shared_data(metadata)?;
+            archive.budget().spend(metadata)?;
+            SeekableReader::new(metadata, Box::new(file_data))
+        };
+        read().map_err(in_entry(archive.archive_offset(), metadata))
+    }
+
+    /// Returns the given file's raw (compressed) data,
+    /// sharing ownership of the archive's.
+    fn shared_data(&self, metadata: &FileMetadata) -> ZipResult<SharedSlice<T>> {
+        self.archive().check_file()?;
+        let archive_offset = self.archive().archive_offset();
+        let mapping = &(*self.data).as_ref()[archive_offset..];
+        let range = data_range(mapping, metadata)?;
+        Ok(SharedSlice {
+            data: self.data.clone(),
+            range: range.start + archive_offset..range.end + archive_offset,
+        })
+    }
+
+    /// Changes the archive with `f`, then builds the tree again,
+    /// since it borrows the archive's entries.
+    fn with_archive<F>(self, f: F) -> ZipResult<Self>
+    where
+        F: FnOnce(&mut ZipArchive) -> ZipResult<()>,
+    {
+        let mut archive = self.tree.into_owner();
+        archive.with_dependent_mut(|_, archive| f(archive))?;
+        Ok(Self {
+            data: self.data,
+            tree: ArchiveTree::build(archive, &self.tree_options)?,
+            tree_options: self.tree_options,
+        })
+    }
+}
+
+impl OwnedZipArchive<ArchiveFile> {
+    /// Opens the ZIP archive at the given path, reading it into memory.
+    ///
+    /// This always reads the whole file, however big it is.
+    /// To memory map larger archives instead, open them with
+    /// [`ArchiveFile::open_mapped()`] (which is `unsafe`, and needs the `mmap` feature)
+    /// and pass that to [`OwnedZipArchive::new()`].
+    /// That maps files of 1 MiB or more and reads smaller ones,
+    /// and the archive checks that a mapped file hasn't been truncated
+    /// before each read (though nothing can make that safe, so don't let it happen).
+    ///
+    /// ```no_run
+    /// # use std::sync::Arc;
+    /// # use piz::*;
+    /// # use piz::read::*;
+    /// let archive = OwnedZipArchive::open("foo.zip")?;
+    /// let metadata = archive.tree().lookup("some/specific/file")?;
+    /// let mut reader = archive.read(metadata)?;
+    ///
+    /// # #[cfg(feature = "mmap")] {
+    /// // SAFETY: Nothing modifies huge.zip while we're reading it.
+    /// let mapped = unsafe { ArchiveFile::open_mapped("huge.zip")? };
+    /// let huge_archive = OwnedZipArchive::new(Arc::new(mapped))?;
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// [`ArchiveFile::open_mapped()`]: ../read/struct.ArchiveFile.html#method.open_mapped
+    /// [`OwnedZipArchive::new()`]: #method.new
+    pub fn open<P: AsRef<Path>>(path: P) -> ZipResult<Self> {
+        Self::new(Arc::new(ArchiveFile::open(path)?))
+    }
+
+    /// Like `OwnedZipArchive::open()`, but allows arbitrary data to prepend the archive.
+    /// Returns the archive and the number of bytes prepended to it.
+    pub fn open_with_prepended_data<P: AsRef<Path>>(path: P) -> ZipResult<(Self, usize)> {
+        Self::with_prepended_data(Arc::new(ArchiveFile::open(path)?))
+    }
+
+    /// Like `OwnedZipArchive::open_with_prepended_data()`,
+    /// but bounds the archive's metadata and builds its tree with the given options,
+    /// like [`OwnedZipArchive::with_options()`].
+    ///
+    /// [`OwnedZipArchive::with_options()`]: #method.with_options
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        open_options: &OpenOptions,
+        tree_options: &TreeOptions,
+    ) -> ZipResult<(Self, usize)> {
+        Self::with_options(
+            Arc::new(ArchiveFile::open(path)?),
+            open_options,
+            tree_options,
+        )
+    }
+}
+
+/// A range of bytes in shared data
+struct SharedSlice<T: ?Sized> {
+    data: Arc<T>,
+    range: Range<usize>,
+}
+
+impl<T: AsRef<[u8]> + ?Sized> AsRef<[u8]> for SharedSlice<T> {
+    fn as_ref(&self) -> &[u8] {
+        &(*self.data).as_ref()[self.range.clone()]
+    }
+}
@@ -0,0 +1,408 @@
+//! Reading many files out of an archive at once
+//!
+//! Since readers are `Send`, users can do this themselves
+//! (see the crate docs for an example with Rayon's `par_bridge()`),
+//! but it's the whole point of piz, so we might as well make it easy.
+
+use std::cmp::Reverse;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crc32fast::Hasher;
+use log::*;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::read::*;
+use crate::result::*;
+
+/// How far along [`ZipArchive::read_parallel()`] is
+///
+/// [`ZipArchive::read_parallel()`]: ../read/struct.ZipArchive.html#method.read_parallel
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Decompressed bytes read so far, across all entries
+    pub bytes_done: u64,
+    /// The total uncompressed size of all entries being read
+    pub bytes_total: u64,
+    /// Entries whose sinks have finished
+    pub entries_done: usize,
+    /// The number of entries being read
+    pub entries_total: usize,
+}
+
+/// Called with [`Progress`] updates
+type ProgressCallback<'p> = dyn Fn(&Progress) + Sync + 'p;
+
+/// Where [`ParallelOptions`] should run
+enum Pool<'p> {
+    /// Rayon's global thread pool
+    Global,
+    /// A new pool with the given number of threads
+    Threads(usize),
+    /// A pool provided by the user
+    Borrowed(&'p ThreadPool),
+}
+
+/// The order [`ZipArchive::read_parallel()`] starts reading entries in
+///
+/// [`ZipArchive::read_parallel()`]: ../read/struct.ZipArchive.html#method.read_parallel
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Schedule {
+    /// Start the biggest entries first, and hand out smaller ones
+    /// to threads as they free up.
+    ///
+    /// This keeps one huge entry at the end of the archive
+    /// from leaving every other thread idle while it finishes.
+    #[default]
+    LargestFirst,
+    /// Start entries in the order they were given.
+    InOrder,
+}
+
+/// Options for [`ZipArchive::read_parallel()`]
+///
+/// [`ZipArchive::read_parallel()`]: ../read/struct.ZipArchive.html#method.read_parallel
+pub struct ParallelOptions<'p> {
+    pool: Pool<'p>,
+    schedule: Schedule,
+    progress: Option<Box<ProgressCallback<'p>>>,
+}
+
+impl Default for ParallelOptions<'_> {
+    fn default() -> Self {
+        Self {
+            pool: Pool::Global,
+            schedule: Schedule::default(),
+            progress: None,
+        }
+    }
+}
+
+impl<'p> ParallelOptions<'p> {
+    /// Creates the default options, which run on Rayon's global thread pool,
+    /// read the largest entries first, and report no progress.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs on a new thread pool with the given number of threads.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.pool = Pool::Threads(threads);
+        self
+    }
+
+    /// Runs on the given thread pool.
+    pub fn thread_pool(mut self, pool: &'p ThreadPool) -> Self {
+        self.pool = Pool::Borrowed(pool);
+        self
+    }
+
+    /// Sets the order entries are read in.
+    pub fn schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Calls the given function with the current [`Progress`]
+    /// as data is decompressed and as each entry finishes.
+    ///
+    /// It's called from many threads at once, and often, so keep it quick!
+    pub fn progress<F: Fn(&Progress) + Sync + 'p>(mut self, callback: F) -> Self {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Runs the given function in the chosen thread pool.
+    fn install<R, F>(&self, op: F) -> ZipResult<R>
+    where
+        R: Send,
+        F: FnOnce() -> ZipResult<R> + Send,
+    {
+        match &self.pool {
+            Pool::Global => op(),
+            Pool::Threads(threads) => ThreadPoolBuilder::new()
+                .num_threads(*threads)
+                .build()
+                .map_err(|e| ZipError::Io(io::Error::other(e)))?
+                .install(op),
+            Pool::Borrowed(pool) => pool.install(op),
+        }
+    }
+}
+
+/// Shared between threads to count how far along we are
+struct Tracker<'p> {
+    bytes_done: AtomicU64,
+    entries_done: AtomicUsize,
+    bytes_total: u64,
+    entries_total: usize,
+    callback: Option<&'p ProgressCallback<'p>>,
+}
+
+impl Tracker<'_> {
+    fn add_bytes(&self, bytes: usize) {
+        self.bytes_done.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.report();
+    }
+
+    fn finish_entry(&self) {
+        self.entries_done.fetch_add(1, Ordering::Relaxed);
+        self.report();
+    }
+
+    fn report(&self) {
+        if let Some(callback) = self.callback {
+            callback(&Progress {
+                bytes_done: self.bytes_done.load(Ordering::Relaxed),
+                bytes_total: self.bytes_total,
+                entries_done: self.entries_done.load(Ordering::Relaxed),
+                entries_total: self.entries_total,
+            });
+        }
+    }
+}
+
+/// Counts bytes as they're read, for progress reporting
+struct ProgressReader<'t, 'p, R> {
+    inner: R,
+    tracker: &'t Tracker<'p>,
+}
+
+impl<R: io::Read> io::Read for ProgressReader<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if count > 0 {
+            self.tracker.add_bytes(count);
+        }
+        Ok(count)
+    }
+}
+
+/// Roughly how long an entry will take to read, for scheduling
+///
+/// Inflating takes time proportional to the output more than the input
+/// (5 GB of zeroes compresses to a few megabytes, but still takes a while),
+/// so go by whichever size is bigger.
+fn estimated_cost(entry: &FileMetadata) -> usize {
+    entry.size.max(entry.compressed_size)
+}
+
+/// Opens each entry with `open()` and passes the reader to `sink()`,
+/// in parallel per the given options.
+pub(crate) fn read_in_parallel<'r, O, S>(
+    mut entries: Vec<&FileMetadata>,
+    options: &ParallelOptions,
+    open: O,
+    sink: S,
+) -> ZipResult<()>
+where
+    O: Fn(&FileMetadata) -> ZipResult<Box<dyn io::Read + Send + 'r>> + Sync,
+    S: Fn(&FileMetadata, &mut dyn io::Read) -> ZipResult<()> + Sync,
+{
+    let tracker = Tracker {
+        bytes_done: AtomicU64::new(0),
+        entries_done: AtomicUsize::new(0),
+        bytes_total: entries.iter().map(|e| e.size as u64).sum(),
+        entries_total: entries.len(),
+        callback: options.progress.as_deref(),
+    };
+    debug!(
+        "Reading {} entries ({} bytes) in parallel",
+        tracker.entries_total, tracker.bytes_total
+    );
+
+    if options.schedule == Schedule::LargestFirst {
+        entries.sort_by_key(|e| Reverse(estimated_cost(e)));
+    }
+
+    let read_entry = |entry: &FileMetadata| {
+        let mut reader = ProgressReader {
+            inner: open(entry)?,
+            tracker: &tracker,
+        };
+        sink(entry, &mut reader)?;
+        tracker.finish_entry();
+        Ok(())
+    };
+
+    // Rayon splits work into contiguous chunks of the list,
+    // which doesn't guarantee that entries start in order.
+    // Instead, have a worker per thread pull the next entry off a shared counter,
+    // so that whoever frees up first takes the next biggest job.
+    let next = AtomicUsize::new(0);
+    let run = || {
+        let workers = rayon::current_num_threads().min(entries.len());
+        (0..workers)
+            .into_par_iter()
+            .with_max_len(1)
+            .try_for_each(|_| loop {
+                let Some(entry) = entries.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                    return Ok(());
+                };
+                if let Err(e) = read_entry(entry) {
+                    // Stop the other workers from starting anything new.
+                    next.store(entries.len(), Ordering::Relaxed);
+                    return Err(e);
+                }
+            })
+    };
+
+    options.install(run)
+}
+
+/// Stored entries are copied (and checksummed) in chunks of this size.
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Copies a stored entry's data into `buf` in parallel chunks,
+/// combining each chunk's CRC32 to check the whole thing.
+pub(crate) fn copy_stored_in_parallel(
+    metadata: &FileMetadata,
+    data: &[u8],
+    buf: &mut [u8],
+    options: &ParallelOptions,
+) -> ZipResult<()> {
+    let data = stored_data(metadata, data)?;
+    assert_eq!(
+        buf.len(),
+        metadata.size,
+        "Buffer must be exactly the size of the file"
+    );
+
+    let tracker = Tracker {
+        bytes_done: AtomicU64::new(0),
+        entries_done: AtomicUsize::new(0),
+        bytes_total: metadata.size as u64,
+        entries_total: 1,
+        callback: options.progress.as_deref(),
+    };
+    debug!(
+        "Copying {} ({} bytes) in {} byte chunks",
+        metadata.path, metadata.size, CHUNK_SIZE
+    );
+
+    let hasher = options.install(|| {
+        // Reductions keep the chunks in order,
+        // which is all combine() needs to get the right CRC.
+        Ok(buf
+            .par_chunks_mut(CHUNK_SIZE)
+            .zip(data.par_chunks(CHUNK_SIZE))
+            .map(|(to, from)| {
+                to.copy_from_slice(from);
+                let mut hasher = Hasher::new();
+                hasher.update(from);
+                tracker.add_bytes(from.len());
+                hasher
+            })
+            .reduce(Hasher::new, |mut left, right| {
+                left.combine(&right);
+                left
+            }))
+    })?;
+    let actual = hasher.finalize();
+    if actual != metadata.crc32 {
+        return Err(ZipError::ChecksumMismatch {
+            expected: metadata.crc32,
+            actual,
+        });
+    }
+    tracker.finish_entry();
+    Ok(())
+}
+
+impl<'a> ZipArchive<'a> {
+    /// Reads the given entries in parallel, passing each one's reader to `sink`.
+    ///
+    /// Entries are read on Rayon's global thread pool unless the options
+    /// say otherwise, and can report [`Progress`] as they go.
+    /// By default, the largest entries are started first (see [`Schedule`]),
+    /// so the whole batch finishes as soon as possible.
+    /// Reading stops at the first error, from either piz or the sink.
+    ///
+    /// ```no_run
+    /// # use std::fs::{self, File};
+    /// # use std::io;
+    /// # use piz::*;
+    /// # use piz::read::*;
+    /// let bytes = fs::read("foo.zip")?;
+    /// let archive = ZipArchive::new(&bytes)?;
+    /// let tree = as_tree(archive.entries())?;
+    ///
+    /// let options = ParallelOptions::new()
+    ///     .threads(4)
+    ///     .progress(|p| eprint!("\r{}/{} bytes", p.bytes_done, p.bytes_total));
+    /// archive.read_parallel(tree.files(), &options, |entry, reader| {
+    ///     if let Some(parent) = entry.path.parent() {
+    ///         fs::create_dir_all(parent)?;
+    ///     }
+    ///     io::copy(reader, &mut File::create(&*entry.path)?)?;
+    ///     Ok(())
+    /// })?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn read_parallel<'e, 'f: 'e, I, S>(
+        &self,
+        entries: I,
+        options: &ParallelOptions,
+        sink: S,
+    ) -> ZipResult<()>
+    where
+        I: IntoIterator<Item = &'e FileMetadata<'f>>,
+        S: Fn(&FileMetadata, &mut dyn io::Read) -> ZipResult<()> + Sync,
+    {
+        let entries = entries.into_iter().collect();
+        read_in_parallel(entries, options, |entry| self.read(entry), sink)
+    }
+
+    /// Copies a stored (uncompressed) file into `buf` using many threads,
+    /// checking its CRC32 along the way.
+    ///
+    /// A stored file's data is just a range of the archive,
+    /// so a huge one can be copied in chunks across all cores
+    /// instead of bottlenecking on a single reader.
+    /// To copy straight into a file, size it and map it:
+    ///
+    /// ```no_run
+    /// # use std::fs::{self, OpenOptions};
+    /// # use memmap2::MmapMut;
+    /// # use piz::*;
+    /// # use piz::read::*;
+    /// let bytes = fs::read("disks.zip")?;
+    /// let archive = ZipArchive::new(&bytes)?;
+    /// let tree = as_tree(archive.entries())?;
+    /// let image = tree.lookup("disk.img")?;
+    ///
+    /// let out = OpenOptions::new()
+    ///     .read(true)
+    ///     .write(true)
+    ///     .create(true)
+    ///     .truncate(true)
+    ///     .open("disk.img")?;
+    /// out.set_len(image.size as u64)?;
+    /// let mut mapping = unsafe { MmapMut::map_mut(&out)? };
+    /// archive.read_stored_parallel(image, &mut mapping, &ParallelOptions::new())?;
+    /// mapping.flush()?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// Fails with [`ZipError::NotStored`] if the file is compressed.
+    ///
+    /// # Panics
+    ///
+    /// If `buf` isn't exactly `metadata.size` bytes long.
+    ///
+    /// [`ZipError::NotStored`]: ../result/enum.ZipError.html#variant.NotStored
+    pub fn read_stored_parallel(
+        &self,
+        metadata: &FileMetadata,
+        buf: &mut [u8],
+        options: &ParallelOptions,
+    ) -> ZipResult<()> {
+        let mut read = || {
+            let data = self.compressed_data(metadata)?;
+            self.budget().spend(metadata)?;
+            copy_stored_in_parallel(metadata, data, buf, options)
+        };
+        read().map_err(in_entry(self.archive_offset(), metadata))
+    }
+}
@@ -10,19 +10,37 @@
 //! [`ZipArchive`]: struct.ZipArchive.html
 
 use std::borrow::Cow;
-use std::collections::{btree_map, BTreeMap};
+use std::collections::{btree_map, BTreeMap, HashMap};
 use std::io;
+use std::ops::Range;
 
 use camino::{Utf8Component, Utf8Path};
 use chrono::NaiveDateTime;
-use flate2::read::DeflateDecoder;
 use log::*;
 
 use crate::arch::usize;
 use crate::crc_reader::Crc32Reader;
+use crate::deflate::deflate;
+use crate::insensitive::{exact_path, fold_path};
+use crate::integrity::test_entries;
+use crate::limits::Budget;
+use crate::recover::recover_entries;
 use crate::result::*;
 use crate::spec;
 
+pub use crate::decoders::{Decoder, Decoders};
+pub use crate::file::ArchiveFile;
+pub use crate::glob::Glob;
+pub use crate::insensitive::CaseInsensitiveIndex;
+pub use crate::integrity::{TestFailure, TestReport};
+pub use crate::limits::{Limits, OpenOptions};
+pub use crate::owned::OwnedZipArchive;
+#[cfg(feature = "parallel")]
+pub use crate::parallel::{ParallelOptions, Progress, Schedule};
+pub use crate::recover::{RecoveryFailure, RecoveryReport};
+pub use crate::sanitize::{sanitize_entries, PathFix, PathRewrite};
+pub use crate::seek::{SeekIndex, SeekableReader};
+
 // Move types into some submodule if we have a handful?
 
 /// The compression method used to store a file
@@ -35,12 +53,13 @@ pub enum CompressionMethod {
     Deflate,
     /// The file is compressed with a yet-unsupported format.
     /// (The u16 indicates the internal format code.)
+    /// Register a [`Decoder`] for it to read such files.
     Unsupported(u16),
 }
 
 /// Metadata for a file or directory in the archive,
 /// retrieved from its central directory
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FileMetadata<'a> {
     /// Uncompressed size of the file in bytes
     pub size: usize,
@@ -72,6 +91,13 @@ pub struct FileMetadata<'a> {
 
     /// The offset to the local file header in the archive
     pub(crate) header_offset: usize,
+
+    /// The entry's position in the central directory
+    pub(crate) index: usize,
+
+    /// True if `path` was rewritten by [`sanitize_entries()`]
+    /// and no longer matches the one stored in the archive.
+    pub(crate) sanitized: bool,
 }
 
 impl FileMetadata<'_> {
@@ -99,8 +125,17 @@ impl FileMetadata<'_> {
 pub struct ZipArchive<'a> {
     /// The contents of the ZIP archive, as a byte slice.
     mapping: &'a [u8],
+    /// How many bytes of other data came before `mapping`
+    archive_offset: usize,
     /// A list of entries from the ZIP's central directory
     entries: Vec<FileMetadata<'a>>,
+    /// Decoders for compression methods we don't know ourselves
+    decoders: Decoders,
+    /// How much our readers may still hand out
+    budget: Budget,
+    /// The file `mapping` came from, if we know it,
+    /// to check that it wasn't truncated before we read from it
+    file: Option<&'a ArchiveFile>,
 }
 
 impl<'a> ZipArchive<'a> {
@@ -139,25 +174,41 @@ impl<'a> ZipArchive<'a> {
     /// Since a ZIP archive's metadata sits at the back of the file,
     /// many formats consist of ZIP archives prepended with some other data.
     /// For example, a self-extracting archive is one with an executable in the front.
-    pub fn with_prepended_data(mut mapping: &'a [u8]) -> ZipResult<(Self, usize)> {
+    pub fn with_prepended_data(mapping: &'a [u8]) -> ZipResult<(Self, usize)> {
+        Self::with_options(mapping, &OpenOptions::default())
+    }
+
+    /// Like `ZipArchive::with_prepended_data()`, but bounds how many entries
+    /// (and how large a central directory, how long paths, etc.)
+    /// the archive can have with the given options.
+    ///
+    /// Useful for untrusted archives, since their central directory could claim
+    /// billions of entries. Exceeding a limit fails with [`ZipError::LimitExceeded`].
+    ///
+    /// [`ZipError::LimitExceeded`]: ../result/enum.ZipError.html#variant.LimitExceeded
+    pub fn with_options(mut mapping: &'a [u8], options: &OpenOptions) -> ZipResult<(Self, usize)> {
         let eocdr_posit = spec::find_eocdr(mapping)?;
-        let eocdr = spec::EndOfCentralDirectory::parse(&mapping[eocdr_posit..])?;
+        let eocdr = spec::EndOfCentralDirectory::parse(&mapping[eocdr_posit..])
+            .map_err(|e| e.at(eocdr_posit))?;
         trace!("{:?}", eocdr);
 
         if eocdr.disk_number != eocdr.disk_with_central_directory {
-            return Err(ZipError::UnsupportedArchive(format!(
-                "No support for multi-disk archives: disk ({}) != disk with central directory ({})",
+            return Err(ZipError::MultiDisk(format!(
+                "disk ({}) != disk with central directory ({})",
                 eocdr.disk_number, eocdr.disk_with_central_directory
-            )));
+            ))
+            .at(eocdr_posit));
         }
         if eocdr.entries != eocdr.entries_on_this_disk {
-            return Err(ZipError::UnsupportedArchive(format!(
-                "No support for multi-disk archives: entries ({}) != entries this disk ({})",
+            return Err(ZipError::MultiDisk(format!(
+                "entries ({}) != entries this disk ({})",
                 eocdr.entries, eocdr.entries_on_this_disk
-            )));
+            ))
+            .at(eocdr_posit));
         }
 
         let nominal_central_directory_offset: usize;
+        let central_directory_size: u64;
         let entry_count: u64;
 
         // Zip files can be prepended by arbitrary junk,
@@ -176,16 +227,18 @@ impl<'a> ZipArchive<'a> {
             trace!("{:?}", zip64_eocdr_locator);
 
             if eocdr.disk_number as u32 != zip64_eocdr_locator.disk_with_central_directory {
-                return Err(ZipError::UnsupportedArchive(format!(
-                    "No support for multi-disk archives: disk ({}) != disk with zip64 central directory ({})",
+                return Err(ZipError::MultiDisk(format!(
+                    "disk ({}) != disk with zip64 central directory ({})",
                     eocdr.disk_number, zip64_eocdr_locator.disk_with_central_directory
-                )));
+                ))
+                .at(zip64_eocdr_locator_posit));
             }
             if zip64_eocdr_locator.disks != 1 {
-                return Err(ZipError::UnsupportedArchive(format!(
-                    "No support for multi-disk archives: Zip64 EOCDR locator reports {} disks",
+                return Err(ZipError::MultiDisk(format!(
+                    "Zip64 EOCDR locator reports {} disks",
                     zip64_eocdr_locator.disks
-                )));
+                ))
+                .at(zip64_eocdr_locator_posit));
             }
 
             // Search for the zip64 EOCDR, from its nominal starting position
@@ -196,8 +249,12 @@ impl<'a> ZipArchive<'a> {
                 .ok_or(ZipError::InvalidArchive(
                     "Too small for Zip64 End Of Central Directory Record",
                 ))?;
-            let zip64_eocdr_search_space =
-                &mapping[zip64_eocdr_search_start..zip64_eocdr_search_end];
+            let zip64_eocdr_search_space = mapping
+                .get(zip64_eocdr_search_start..zip64_eocdr_search_end)
+                .ok_or(ZipError::InvalidArchive(
+                    "Invalid Zip64 End Of Central Directory Record offset",
+                ))
+                .map_err(|e| e.at(zip64_eocdr_locator_posit))?;
 
             let zip64_eocdr_posit = spec::find_zip64_eocdr(zip64_eocdr_search_space)?;
             // Since we're searching starting at the provided offset,
@@ -205,11 +262,13 @@ impl<'a> ZipArchive<'a> {
             archive_offset = zip64_eocdr_posit;
             let zip64_eocdr = spec::Zip64EndOfCentralDirectory::parse(
                 &zip64_eocdr_search_space[zip64_eocdr_posit..],
-            )?;
+            )
+            .map_err(|e| e.at(zip64_eocdr_search_start + zip64_eocdr_posit))?;
 
             trace!("{:?}", zip64_eocdr);
 
             nominal_central_directory_offset = usize(zip64_eocdr.central_directory_offset)?;
+            central_directory_size = zip64_eocdr.central_directory_size;
             entry_count = zip64_eocdr.entries;
         } else {
             // The offset is the actual position versus the stored one.
@@ -219,8 +278,10 @@ impl<'a> ZipArchive<'a> {
                 .and_then(|off| off.checked_sub(nominal_offset))
                 .ok_or(ZipError::InvalidArchive(
                     "Invalid central directory size or offset",
-                ))?;
+                ))
+                .map_err(|e| e.at(eocdr_posit))?;
             nominal_central_directory_offset = usize(eocdr.central_directory_offset)?;
+            central_directory_size = eocdr.central_directory_size as u64;
             entry_count = eocdr.entries as u64;
         }
 
@@ -231,20 +292,90 @@ impl<'a> ZipArchive<'a> {
             nominal_central_directory_offset
         );
 
-        let mut central_directory = &mapping[nominal_central_directory_offset..];
+        options.check_directory(entry_count, central_directory_size)?;
 
-        let mut entries = Vec::with_capacity(usize(entry_count)?);
+        // Only parse the central directory we were told about (and checked),
+        // not everything from its offset to the end of the archive.
+        let mut central_directory = usize(central_directory_size)?
+            .checked_add(nominal_central_directory_offset)
+            .and_then(|end| mapping.get(nominal_central_directory_offset..end))
+            .ok_or(ZipError::InvalidArchive(
+                "Central directory runs past the end of the archive",
+            ))?;
 
-        for _ in 0..entry_count {
-            let dir_entry = spec::CentralDirectoryEntry::parse_and_consume(&mut central_directory)?;
+        // Don't take the entry count's word for how much to allocate:
+        // the central directory has to have room for them all.
+        let max_entries =
+            central_directory.len() / spec::CentralDirectoryEntry::fixed_size_in_file();
+        let mut entries = Vec::with_capacity(usize(entry_count.min(max_entries as u64))?);
+
+        let central_directory_end =
+            archive_offset + nominal_central_directory_offset + central_directory.len();
+        for index in 0..usize(entry_count)? {
+            let entry_posit = central_directory_end - central_directory.len();
+            let in_entry = |e: ZipError| e.for_index(index).at(entry_posit);
+            let dir_entry = spec::CentralDirectoryEntry::parse_and_consume(&mut central_directory)
+                .map_err(in_entry)?;
             trace!("{:?}", dir_entry);
+            options.check_entry(&dir_entry).map_err(in_entry)?;
 
-            let file_metadata = FileMetadata::from_cde(&dir_entry)?;
+            let file_metadata = FileMetadata::from_cde(&dir_entry, index).map_err(in_entry)?;
             debug!("{:?}", file_metadata);
             entries.push(file_metadata);
         }
 
-        Ok((ZipArchive { mapping, entries }, archive_offset))
+        Ok((
+            ZipArchive {
+                mapping,
+                archive_offset,
+                entries,
+                decoders: Decoders::default(),
+                budget: Budget::default(),
+                file: None,
+            },
+            archive_offset,
+        ))
+    }
+
+    /// Salvages whatever entries it can from a damaged archive, like `zip -FF`,
+    /// by scanning it for local file headers instead of going by its central directory.
+    ///
+    /// Handy when [`new()`](Self::new) fails because the archive was truncated
+    /// or its central directory is otherwise missing or mangled.
+    /// Each file's data is found from the sizes in its local header,
+    /// or from the data descriptor after it.
+    /// Recovered entries lack what only the central directory has
+    /// (Unix permissions, for one), and their data hasn't been checked yet;
+    /// [`test()`](Self::test) them to see which are intact.
+    ///
+    /// ```no_run
+    /// # use std::fs;
+    /// # use piz::*;
+    /// let bytes = fs::read("truncated.zip")?;
+    /// let archive = match ZipArchive::new(&bytes) {
+    ///     Ok(archive) => archive,
+    ///     Err(e) => {
+    ///         eprintln!("{e}; recovering what we can");
+    ///         let (archive, report) = ZipArchive::recover(&bytes);
+    ///         for failure in &report.failures {
+    ///             eprintln!("Lost something at {}: {}", failure.offset, failure.error);
+    ///         }
+    ///         archive
+    ///     }
+    /// };
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn recover(mapping: &'a [u8]) -> (Self, RecoveryReport) {
+        let (entries, report) = recover_entries(mapping);
+        let archive = ZipArchive {
+            mapping,
+            archive_offset: 0,
+            entries,
+            decoders: Decoders::default(),
+            budget: Budget::default(),
+            file: None,
+        };
+        (archive, report)
     }
 
     /// Returns the entries found in the ZIP archive's central directory.
@@ -253,57 +384,414 @@ impl<'a> ZipArchive<'a> {
     /// To do that, create a [`FileTree`].
     ///
     /// [`FileTree`]: struct.FileTree.html
-    pub fn entries(&self) -> &[FileMetadata] {
+    pub fn entries(&self) -> &[FileMetadata<'_>] {
         &self.entries
     }
 
+    /// Decodes files with compression methods piz doesn't support itself
+    /// using the given decoders.
+    pub fn with_decoders(mut self, decoders: Decoders) -> Self {
+        self.set_decoders(decoders);
+        self
+    }
+
+    /// Like `with_decoders()`, but in place.
+    pub(crate) fn set_decoders(&mut self, decoders: Decoders) {
+        self.decoders = decoders;
+    }
+
+    /// Checks the archive's entries against the given limits,
+    /// and enforces them on reads from here on out.
+    ///
+    /// Fails if there are too many entries, if they declare too many bytes
+    /// (in total or compared to their compressed size),
+    /// or if any entries overlap each other, like a zip bomb's.
+    /// Afterwards, each read reserves its file's size from the total,
+    /// failing with [`ZipError::LimitExceeded`] once that runs out.
+    ///
+    /// [`ZipError::LimitExceeded`]: ../result/enum.ZipError.html#variant.LimitExceeded
+    pub fn with_limits(mut self, limits: Limits) -> ZipResult<Self> {
+        self.set_limits(limits)?;
+        Ok(self)
+    }
+
+    /// Like `with_limits()`, but in place.
+    pub(crate) fn set_limits(&mut self, limits: Limits) -> ZipResult<()> {
+        limits.check_entries(self.mapping, &self.entries)?;
+        self.budget = Budget::new(limits);
+        Ok(())
+    }
+
     /// Reads the given file from the ZIP archive.
     ///
     /// Since each file in a ZIP archive is compressed independently,
     /// multiple files can be read in parallel.
     pub fn read(&self, metadata: &FileMetadata) -> ZipResult<Box<dyn io::Read + Send + 'a>> {
-        let mut file_slice = &self.mapping[metadata.header_offset..];
-        let local_header = spec::LocalFileHeader::parse_and_consume(&mut file_slice)?;
-        trace!("{:?}", local_header);
-        let local_metadata = FileMetadata::from_local_header(&local_header, metadata)?;
-        debug!("Reading {:?}", local_metadata);
-        if cfg!(feature = "check-local-metadata") && *metadata != local_metadata {
+        let read = || {
+            let data = self.compressed_data(metadata)?;
+            self.budget.spend(metadata)?;
+            make_reader(metadata, io::Cursor::new(data), &self.decoders)
+        };
+        read().map_err(in_entry(self.archive_offset, metadata))
+    }
+
+    /// Reads the given file from the ZIP archive with a reader that can [`Seek`].
+    ///
+    /// Handy for formats that want random access, like SQLite databases
+    /// or Parquet files (whose metadata is at the end).
+    /// Seeking backwards in a compressed file decompresses it again from the start,
+    /// so prefer [`read()`](Self::read) for plain old streaming.
+    ///
+    /// ```no_run
+    /// # use std::fs;
+    /// # use std::io::{Read, Seek, SeekFrom};
+    /// # use piz::*;
+    /// # use piz::read::*;
+    /// let bytes = fs::read("foo.zip")?;
+    /// let archive = ZipArchive::new(&bytes)?;
+    /// let tree = as_tree(archive.entries())?;
+    /// let mut reader = archive.read_seekable(tree.lookup("data.parquet")?)?;
+    /// let mut footer = [0; 8];
+    /// reader.seek(SeekFrom::End(-8))?;
+    /// reader.read_exact(&mut footer)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// [`Seek`]: std::io::Seek
+    pub fn read_seekable(&self, metadata: &FileMetadata) -> ZipResult<SeekableReader<'a>> {
+        let read = || {
+            let data = self.compressed_data(metadata)?;
+            self.budget.spend(metadata)?;
+            SeekableReader::new(metadata, Box::new(data))
+        };
+        read().map_err(in_entry(self.archive_offset, metadata))
+    }
+
+    /// Returns the budget our reads draw from.
+    pub(crate) fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    /// Returns the decoders our reads use for methods we don't know.
+    pub(crate) fn decoders(&self) -> &Decoders {
+        &self.decoders
+    }
+
+    /// Returns how many bytes of other data came before the archive.
+    pub(crate) fn archive_offset(&self) -> usize {
+        self.archive_offset
+    }
+
+    /// Returns the archive's data, after any prepended data.
+    pub(crate) fn mapping(&self) -> &'a [u8] {
+        self.mapping
+    }
+
+    /// Notes the file our data came from, so we can check it before reading.
+    pub(crate) fn set_file(&mut self, file: &'a ArchiveFile) {
+        self.file = Some(file);
+    }
+
+    /// Copies entries' paths out of the mapping,
+    /// so that looking at them can't touch pages a truncated file no longer has.
+    pub(crate) fn own_paths(&mut self) {
+        for entry in &mut self.entries {
+            if let Cow::Borrowed(path) = entry.path {
+                entry.path = Cow::Owned(path.to_owned());
+            }
+        }
+    }
+
+    /// Fails if our data came from a mapped file that's since been truncated.
+    pub(crate) fn check_file(&self) -> ZipResult<()> {
+        self.file.map_or(Ok(()), ArchiveFile::check_unchanged)
+    }
+
+    /// Returns the given file's raw (compressed) data.
+    pub(crate) fn compressed_data(&self, metadata: &FileMetadata) -> ZipResult<&'a [u8]> {
+        self.check_file()?;
+        Ok(&self.mapping[data_range(self.mapping, metadata)?])
+    }
+
+    /// Tests every entry in the archive, like `unzip -t`.
+    ///
+    /// Each entry is decompressed (in parallel, with the `parallel` feature),
+    /// its CRC32 and size are checked, and its local file header is compared
+    /// to the central directory's entry.
+    /// Instead of stopping at the first problem,
+    /// this returns a report of every entry that failed and why.
+    /// Testing an entry counts against [`with_limits()`](Self::with_limits)
+    /// just like reading it does, so entries past the budget fail with
+    /// [`ZipError::LimitExceeded`].
+    ///
+    /// ```no_run
+    /// # use std::fs;
+    /// # use piz::*;
+    /// # use piz::read::*;
+    /// let bytes = fs::read("upload.zip")?;
+    /// let archive = ZipArchive::new(&bytes)?;
+    /// let report = archive.test();
+    /// for failure in &report.failures {
+    ///     eprintln!("{}: {}", failure.entry.path, failure.error);
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn test(&self) -> TestReport<'_> {
+        test_entries(self)
+    }
+
+    /// Reads the given file from the ZIP archive into a new `Vec`.
+    ///
+    /// Since we know the file's size up front, this allocates once
+    /// and decompresses the whole thing in one shot,
+    /// which beats calling `read_to_end()` on [`read()`](Self::read)'s reader.
+    /// The file's size and CRC32 are checked.
+    pub fn read_to_vec(&self, metadata: &FileMetadata) -> ZipResult<Vec<u8>> {
+        let read = || {
+            // Find the data first so we know its (compressed) size is real.
+            let data = self.compressed_data(metadata)?;
+            self.budget.spend(metadata)?;
+            let mut buf = file_buffer(metadata, &self.decoders)?;
+            decompress_into(metadata, data, &mut buf, &self.decoders)?;
+            Ok(buf)
+        };
+        read().map_err(in_entry(self.archive_offset, metadata))
+    }
+
+    /// Reads the given file from the ZIP archive into `buf`,
+    /// like [`read_to_vec()`](Self::read_to_vec).
+    ///
+    /// # Panics
+    ///
+    /// If `buf` isn't exactly `metadata.size` bytes long.
+    pub fn read_into(&self, metadata: &FileMetadata, buf: &mut [u8]) -> ZipResult<()> {
+        let mut read = || {
+            let data = self.compressed_data(metadata)?;
+            self.budget.spend(metadata)?;
+            decompress_into(metadata, data, buf, &self.decoders)
+        };
+        read().map_err(in_entry(self.archive_offset, metadata))
+    }
+
+    /// Returns a stored (uncompressed) file's contents, straight from the archive,
+    /// without any copying.
+    ///
+    /// Fails with [`ZipError::NotStored`] if the file is compressed.
+    /// Since no reading happens, neither does a CRC check;
+    /// use [`stored_bytes_checked()`](Self::stored_bytes_checked) for that.
+    ///
+    /// ```no_run
+    /// # use std::fs::File;
+    /// # use memmap2::Mmap;
+    /// # use piz::*;
+    /// # use piz::read::*;
+    /// let zip_file = File::open("assets.zip")?;
+    /// let mapping = unsafe { Mmap::map(&zip_file)? };
+    /// let archive = ZipArchive::new(&mapping)?;
+    /// let tree = as_tree(archive.entries())?;
+    /// let texture: &[u8] = archive.stored_bytes(tree.lookup("textures/grass.dds")?)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// [`ZipError::NotStored`]: ../result/enum.ZipError.html#variant.NotStored
+    pub fn stored_bytes(&self, metadata: &FileMetadata) -> ZipResult<&'a [u8]> {
+        self.compressed_data(metadata)
+            .and_then(|data| stored_data(metadata, data))
+            .map_err(in_entry(self.archive_offset, metadata))
+    }
+
+    /// Like [`stored_bytes()`](Self::stored_bytes),
+    /// but also checks the file's CRC32 (which means touching every byte of it).
+    pub fn stored_bytes_checked(&self, metadata: &FileMetadata) -> ZipResult<&'a [u8]> {
+        check_crc32(metadata, self.stored_bytes(metadata)?)
+            .map_err(in_entry(self.archive_offset, metadata))
+    }
+}
+
+/// Returns a function that notes which entry an error was about,
+/// and where its local file header is.
+pub(crate) fn in_entry<'m>(
+    archive_offset: usize,
+    metadata: &'m FileMetadata,
+) -> impl Fn(ZipError) -> ZipError + 'm {
+    move |e| {
+        e.for_entry(metadata)
+            .at(archive_offset + metadata.header_offset)
+    }
+}
+
+/// Finds the given file's (compressed) data in the mapping,
+/// checking its local file header along the way.
+pub(crate) fn data_range(mapping: &[u8], metadata: &FileMetadata) -> ZipResult<Range<usize>> {
+    data_range_with(mapping, metadata, cfg!(feature = "check-local-metadata"))
+}
+
+/// Like `data_range()`, but says whether to check the local file header
+/// against the central directory regardless of features.
+pub(crate) fn data_range_with(
+    mapping: &[u8],
+    metadata: &FileMetadata,
+    check_local_metadata: bool,
+) -> ZipResult<Range<usize>> {
+    let past_end = || ZipError::InvalidArchive("File data runs past the end of the archive");
+    let mut file_slice = mapping.get(metadata.header_offset..).ok_or_else(past_end)?;
+    let local_header = spec::LocalFileHeader::parse_and_consume(&mut file_slice)?;
+    trace!("{:?}", local_header);
+    let local_metadata = FileMetadata::from_local_header(&local_header, metadata)?;
+    debug!("Reading {:?}", local_metadata);
+    if check_local_metadata && *metadata != local_metadata {
+        return Err(ZipError::InvalidArchive(
+            "Central directory entry doesn't match local file header",
+        ));
+    }
+
+    if metadata.encrypted {
+        return Err(ZipError::Encrypted);
+    }
+
+    let start = mapping.len() - file_slice.len();
+    let end = start
+        .checked_add(metadata.compressed_size)
+        .filter(|end| *end <= mapping.len())
+        .ok_or_else(past_end)?;
+    Ok(start..end)
+}
+
+/// Makes sure the given file is stored (not compressed), returning its data.
+pub(crate) fn stored_data<'d>(metadata: &FileMetadata, data: &'d [u8]) -> ZipResult<&'d [u8]> {
+    if metadata.compression_method != CompressionMethod::None {
+        return Err(ZipError::NotStored);
+    }
+    if data.len() != metadata.size {
+        return Err(ZipError::InvalidArchive(
+            "Stored file's size doesn't match its compressed size",
+        ));
+    }
+    Ok(data)
+}
+
+/// Passes the given file's data through if it matches the expected CRC32.
+pub(crate) fn check_crc32<'d>(metadata: &FileMetadata, data: &'d [u8]) -> ZipResult<&'d [u8]> {
+    let actual = crc32fast::hash(data);
+    if actual != metadata.crc32 {
+        return Err(ZipError::ChecksumMismatch {
+            expected: metadata.crc32,
+            actual,
+        });
+    }
+    Ok(data)
+}
+
+/// Deflate can't do better than 1032:1 (a 258-byte match in two bits).
+const MAX_DEFLATE_RATIO: usize = 1032;
+
+/// Allocates a zeroed buffer for the given file,
+/// provided its declared size is one its compressed data could possibly have
+/// (and that we can decompress it at all).
+pub(crate) fn file_buffer(metadata: &FileMetadata, decoders: &Decoders) -> ZipResult<Vec<u8>> {
+    match metadata.compression_method {
+        CompressionMethod::None if metadata.size != metadata.compressed_size => {
             return Err(ZipError::InvalidArchive(
-                "Central directory entry doesn't match local file header",
+                "Stored file's size doesn't match its compressed size",
             ));
         }
-
-        if metadata.encrypted {
-            return Err(ZipError::UnsupportedArchive(format!(
-                "Can't read encrypted file {}",
-                metadata.path
-            )));
+        CompressionMethod::Deflate
+            if metadata.size > metadata.compressed_size.saturating_mul(MAX_DEFLATE_RATIO) =>
+        {
+            return Err(ZipError::InvalidArchive(
+                "Deflated file's size is impossibly large for its compressed size",
+            ));
         }
+        CompressionMethod::Unsupported(method) if decoders.get(method).is_none() => {
+            return Err(ZipError::UnsupportedMethod(method));
+        }
+        _ => {}
+    }
+    // Custom decoders could claim anything, so fail instead of aborting
+    // if we can't allocate that much.
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(metadata.size)
+        .map_err(|_| ZipError::Io(io::ErrorKind::OutOfMemory.into()))?;
+    buf.resize(metadata.size, 0);
+    Ok(buf)
+}
 
-        make_reader(
-            metadata.compression_method,
-            metadata.crc32,
-            io::Cursor::new(&file_slice[0..metadata.compressed_size]),
-        )
+/// Decompresses a file into `buf` all at once, checking its size and CRC32.
+pub(crate) fn decompress_into(
+    metadata: &FileMetadata,
+    data: &[u8],
+    buf: &mut [u8],
+    decoders: &Decoders,
+) -> ZipResult<()> {
+    assert_eq!(
+        buf.len(),
+        metadata.size,
+        "Buffer must be exactly the size of the file"
+    );
+    match metadata.compression_method {
+        CompressionMethod::None => buf.copy_from_slice(stored_data(metadata, data)?),
+        CompressionMethod::Deflate => deflate().decompress_into(data, buf)?,
+        CompressionMethod::Unsupported(method) => {
+            let decoder = decoders
+                .get(method)
+                .ok_or(ZipError::UnsupportedMethod(method))?;
+            let mut reader = decoder.decode(Box::new(data))?;
+            let wrong_size =
+                || ZipError::InvalidArchive("Decoded file doesn't match its declared size");
+            reader.read_exact(buf).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => wrong_size(),
+                _ => ZipError::Io(e),
+            })?;
+            if reader.read(&mut [0])? != 0 {
+                return Err(wrong_size());
+            }
+        }
     }
+    check_crc32(metadata, buf)?;
+    Ok(())
 }
 
 /// Returns a boxed read trait for a compressed file,
-/// given its compression method and expected CRC.
-fn make_reader<'a, R: io::Read + Send + 'a>(
-    compression_method: CompressionMethod,
-    crc32: u32,
+/// which checks its size and CRC against the given metadata.
+pub(crate) fn make_reader<'a, R: io::Read + Send + 'a>(
+    metadata: &FileMetadata,
     reader: R,
+    decoders: &Decoders,
 ) -> ZipResult<Box<dyn io::Read + Send + 'a>> {
-    match compression_method {
-        CompressionMethod::None => Ok(Box::new(Crc32Reader::new(reader, crc32))),
+    let crc32 = metadata.crc32;
+    let size = metadata.size as u64;
+    let checked = |reader: Box<dyn io::Read + Send + 'a>| EntryReader {
+        inner: Crc32Reader::new(reader, crc32, size),
+        context: ErrorContext::entry(metadata),
+    };
+    match metadata.compression_method {
+        CompressionMethod::None => Ok(Box::new(checked(Box::new(reader)))),
         CompressionMethod::Deflate => {
-            let deflate_reader = DeflateDecoder::new(reader);
-            Ok(Box::new(Crc32Reader::new(deflate_reader, crc32)))
+            let deflate_reader =
+                deflate().reader(Box::new(reader), metadata.compressed_size as u64);
+            Ok(Box::new(checked(deflate_reader)))
         }
-        _ => Err(ZipError::UnsupportedArchive(String::from(
-            "Compression method not supported",
-        ))),
+        CompressionMethod::Unsupported(method) => {
+            let decoder = decoders
+                .get(method)
+                .ok_or(ZipError::UnsupportedMethod(method))?;
+            let decoded = decoder.decode(Box::new(reader))?;
+            Ok(Box::new(checked(decoded)))
+        }
+    }
+}
+
+/// Notes which entry any error reading it was about.
+struct EntryReader<R> {
+    inner: R,
+    context: ErrorContext,
+}
+
+impl<R: io::Read> io::Read for EntryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner
+            .read(buf)
+            .map_err(|e| ZipError::from(e).within(&self.context).into())
     }
 }
 
@@ -315,6 +803,9 @@ pub type DirectoryContents<'a> = BTreeMap<&'a str, DirectoryEntry<'a>>;
 pub struct Directory<'a> {
     pub metadata: &'a FileMetadata<'a>,
     pub children: DirectoryContents<'a>,
+    /// Earlier entries for the same directory, in archive order.
+    /// (Only populated by [`DuplicatePolicy::KeepAll`].)
+    pub earlier_versions: Vec<&'a FileMetadata<'a>>,
 }
 
 impl<'a> Directory<'a> {
@@ -322,6 +813,25 @@ impl<'a> Directory<'a> {
         Self {
             metadata,
             children: DirectoryContents::new(),
+            earlier_versions: Vec::new(),
+        }
+    }
+}
+
+/// A file in a ZipArchive
+#[derive(Debug)]
+pub struct FileEntry<'a> {
+    pub metadata: &'a FileMetadata<'a>,
+    /// Earlier entries for the same file, in archive order.
+    /// (Only populated by [`DuplicatePolicy::KeepAll`].)
+    pub earlier_versions: Vec<&'a FileMetadata<'a>>,
+}
+
+impl<'a> FileEntry<'a> {
+    fn new(metadata: &'a FileMetadata<'a>) -> Self {
+        Self {
+            metadata,
+            earlier_versions: Vec::new(),
         }
     }
 }
@@ -331,7 +841,7 @@ impl<'a> Directory<'a> {
 /// [`FileTree`]: struct.FileTree.html
 #[derive(Debug)]
 pub enum DirectoryEntry<'a> {
-    File(&'a FileMetadata<'a>),
+    File(FileEntry<'a>),
     Directory(Directory<'a>),
 }
 
@@ -339,14 +849,91 @@ impl<'a> DirectoryEntry<'a> {
     /// Returns the metadata of the entry.
     pub fn metadata(&self) -> &'a FileMetadata<'a> {
         match &self {
-            DirectoryEntry::File(metadata) => metadata,
+            DirectoryEntry::File(file) => file.metadata,
             DirectoryEntry::Directory(dir) => dir.metadata,
         }
     }
 
-    fn name(&self) -> &'a str {
-        let path = &self.metadata().path;
-        path.file_name().expect("Path ended in ..")
+    /// Returns every entry the archive has for this path, in archive order.
+    ///
+    /// Unless the tree was built with [`DuplicatePolicy::KeepAll`],
+    /// this is just [`metadata()`](#method.metadata).
+    pub fn versions(&self) -> Vec<&'a FileMetadata<'a>> {
+        let (earlier, latest) = match &self {
+            DirectoryEntry::File(file) => (&file.earlier_versions, file.metadata),
+            DirectoryEntry::Directory(dir) => (&dir.earlier_versions, dir.metadata),
+        };
+        let mut versions = earlier.clone();
+        versions.push(latest);
+        versions
+    }
+
+    /// Swaps in new metadata for the same path,
+    /// returning the metadata it replaced.
+    fn replace_metadata(&mut self, new: &'a FileMetadata<'a>) -> &'a FileMetadata<'a> {
+        match self {
+            DirectoryEntry::File(file) => std::mem::replace(&mut file.metadata, new),
+            DirectoryEntry::Directory(dir) => std::mem::replace(&mut dir.metadata, new),
+        }
+    }
+
+    fn earlier_versions_mut(&mut self) -> &mut Vec<&'a FileMetadata<'a>> {
+        match self {
+            DirectoryEntry::File(file) => &mut file.earlier_versions,
+            DirectoryEntry::Directory(dir) => &mut dir.earlier_versions,
+        }
+    }
+}
+
+/// What [`as_tree_with()`] should do when an archive has
+/// more than one entry for the same path.
+///
+/// ZIP archives that have been appended to (e.g., with `zip -u`)
+/// legitimately contain duplicates, where the last one is the "real" file.
+/// In all cases, a path that is a file in one entry and a directory in another
+/// is still an error.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// Fail with a [`ZipError::Hierarchy`] error. (This is what [`as_tree()`] does.)
+    #[default]
+    Error,
+    /// Keep the first entry for the path, ignoring any later ones.
+    FirstWins,
+    /// Keep the last entry for the path, like most unzip tools.
+    LastWins,
+    /// Like `LastWins`, but also keep earlier entries for the path.
+    /// They can be retrieved with [`FileTree::lookup_versions()`]
+    /// or [`DirectoryEntry::versions()`].
+    KeepAll,
+}
+
+/// Options for [`as_tree_with()`]
+#[derive(Debug, Clone, Default)]
+pub struct TreeOptions {
+    duplicates: DuplicatePolicy,
+    reject_case_collisions: bool,
+}
+
+impl TreeOptions {
+    /// Creates the default options, which match [`as_tree()`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets what to do with duplicate entries for the same path.
+    pub fn duplicates(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicates = policy;
+        self
+    }
+
+    /// If true, fail with a [`ZipError::Hierarchy`] error when two paths
+    /// would be the same file on a case-insensitive file system
+    /// (e.g., `README` and `readme`, or NFC and NFD spellings of `café`).
+    ///
+    /// See also [`CaseInsensitiveIndex`].
+    pub fn reject_case_collisions(mut self, reject: bool) -> Self {
+        self.reject_case_collisions = reject;
+        self
     }
 }
 
@@ -360,12 +947,48 @@ impl<'a> DirectoryEntry<'a> {
 /// 2. It validates the archive, making sure each `FileMetadata` has a valid path,
 ///    no duplicates, etc. (The ZIP file format makes no promises here.)
 ///
+/// To clean up odd paths instead of rejecting them, see [`sanitize_entries()`].
+///
 /// [`ZipArchive::entries()`]: struct.ZipArchive.html#method.entries
 pub fn as_tree<'a>(entries: &'a [FileMetadata<'a>]) -> ZipResult<DirectoryContents<'a>> {
+    as_tree_with(entries, &TreeOptions::default())
+}
+
+/// Like [`as_tree()`], but with the given [`TreeOptions`].
+///
+/// ```no_run
+/// # use std::fs;
+/// # use piz::*;
+/// # use piz::read::*;
+/// let bytes = fs::read("appended-to.zip")?;
+/// let archive = ZipArchive::new(&bytes)?;
+/// let options = TreeOptions::new().duplicates(DuplicatePolicy::LastWins);
+/// let tree = as_tree_with(archive.entries(), &options)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn as_tree_with<'a>(
+    entries: &'a [FileMetadata<'a>],
+    options: &TreeOptions,
+) -> ZipResult<DirectoryContents<'a>> {
     let mut contents = DirectoryContents::new();
+    // Maps folded paths to the first (exact) path seen for them
+    let mut folded_paths: HashMap<String, String> = HashMap::new();
 
     for entry in entries {
-        entree_entry(entry, &mut contents)?;
+        if options.reject_case_collisions {
+            let exact = exact_path(&entry.path);
+            let existing = folded_paths
+                .entry(fold_path(&entry.path))
+                .or_insert_with(|| exact.clone());
+            // Exact duplicates are the DuplicatePolicy's problem.
+            if *existing != exact {
+                return Err(ZipError::Hierarchy(format!(
+                    "{} and {existing} collide on case-insensitive file systems",
+                    entry.path
+                )));
+            }
+        }
+        entree_entry(entry, &mut contents, options)?;
     }
 
     Ok(contents)
@@ -375,6 +998,32 @@ pub trait FileTree<'a> {
     /// Looks up a file or directory by its path.
     fn lookup<P: AsRef<Utf8Path>>(&self, path: P) -> ZipResult<&'a FileMetadata<'a>>;
 
+    /// Looks up every entry for the given path, in archive order.
+    ///
+    /// Unless the tree was built with [`DuplicatePolicy::KeepAll`],
+    /// this is just the result of [`lookup()`](#tymethod.lookup).
+    fn lookup_versions<P: AsRef<Utf8Path>>(&self, path: P) -> ZipResult<Vec<&'a FileMetadata<'a>>>;
+
+    /// Looks up a file or directory by its path,
+    /// returning its place in the tree instead of just its metadata.
+    fn lookup_entry<'b, P: AsRef<Utf8Path>>(&'b self, path: P)
+        -> ZipResult<&'b DirectoryEntry<'a>>;
+
+    /// Returns the contents of the directory at the given path,
+    /// which is itself a `FileTree` rooted at that directory.
+    ///
+    /// An empty path returns the whole tree.
+    fn subtree<'b, P: AsRef<Utf8Path>>(&'b self, path: P) -> ZipResult<&'b DirectoryContents<'a>>;
+
+    /// Returns an iterator over the immediate children of the directory
+    /// at the given path, sorted by name.
+    ///
+    /// An empty path lists the top level of the tree.
+    fn list_dir<'b, P: AsRef<Utf8Path>>(
+        &'b self,
+        path: P,
+    ) -> ZipResult<btree_map::Values<'b, &'a str, DirectoryEntry<'a>>>;
+
     /// Returns an iterator over the entries in the tree, sorted by path.
     fn traverse<'b>(&'b self) -> TreeIterator<'a, 'b>;
 
@@ -383,28 +1032,40 @@ pub trait FileTree<'a> {
 
     /// Returns an iterator over the directories in the tree, sorted by path.
     fn directories<'b>(&'b self) -> DirectoryTreeIterator<'a, 'b>;
+
+    /// Returns an iterator over the entries in the tree that pass the given filter,
+    /// sorted by path.
+    ///
+    /// The filter is either a [`Glob`] or a closure that returns a [`Visit`]
+    /// for each entry. Directories the filter prunes are skipped entirely.
+    fn filter<'b, F: TreeFilter<'a>>(&'b self, filter: F) -> FilteredTreeIterator<'a, 'b, F>;
 }
 
 impl<'a> FileTree<'a> for DirectoryContents<'a> {
     fn lookup<P: AsRef<Utf8Path>>(&self, path: P) -> ZipResult<&'a FileMetadata<'a>> {
-        let path = path.as_ref();
-        let parent_dir = if let Some(parent) = path.parent() {
-            match walk_parent_directories(parent, self) {
-                Err(ZipError::NoSuchFile(_)) => Err(ZipError::NoSuchFile(path.to_owned())),
-                other_result => other_result,
-            }?
-        } else {
-            self
-        };
+        find_entry(path.as_ref(), self).map(|dir_entry| dir_entry.metadata())
+    }
 
-        let base = path
-            .file_name()
-            .ok_or_else(|| ZipError::InvalidPath(format!("Path {} ended in ..", path)))?;
+    fn lookup_versions<P: AsRef<Utf8Path>>(&self, path: P) -> ZipResult<Vec<&'a FileMetadata<'a>>> {
+        find_entry(path.as_ref(), self).map(|dir_entry| dir_entry.versions())
+    }
+
+    fn lookup_entry<'b, P: AsRef<Utf8Path>>(
+        &'b self,
+        path: P,
+    ) -> ZipResult<&'b DirectoryEntry<'a>> {
+        find_entry(path.as_ref(), self)
+    }
 
-        parent_dir
-            .get(base)
-            .ok_or_else(|| ZipError::NoSuchFile(path.to_owned()))
-            .map(|dir_entry| dir_entry.metadata())
+    fn subtree<'b, P: AsRef<Utf8Path>>(&'b self, path: P) -> ZipResult<&'b DirectoryContents<'a>> {
+        walk_parent_directories(path.as_ref(), self)
+    }
+
+    fn list_dir<'b, P: AsRef<Utf8Path>>(
+        &'b self,
+        path: P,
+    ) -> ZipResult<btree_map::Values<'b, &'a str, DirectoryEntry<'a>>> {
+        self.subtree(path).map(|dir| dir.values())
     }
 
     fn traverse<'b>(&'b self) -> TreeIterator<'a, 'b> {
@@ -418,12 +1079,40 @@ impl<'a> FileTree<'a> for DirectoryContents<'a> {
     fn directories<'b>(&'b self) -> DirectoryTreeIterator<'a, 'b> {
         DirectoryTreeIterator::new(self)
     }
+
+    fn filter<'b, F: TreeFilter<'a>>(&'b self, filter: F) -> FilteredTreeIterator<'a, 'b, F> {
+        FilteredTreeIterator::new(self, filter)
+    }
+}
+
+/// Finds the entry in the tree at the given (user-provided) path.
+fn find_entry<'a, 'b>(
+    path: &Utf8Path,
+    tree: &'b DirectoryContents<'a>,
+) -> ZipResult<&'b DirectoryEntry<'a>> {
+    let parent_dir = if let Some(parent) = path.parent() {
+        match walk_parent_directories(parent, tree) {
+            Err(ZipError::NoSuchFile(_)) => Err(ZipError::NoSuchFile(path.to_owned())),
+            other_result => other_result,
+        }?
+    } else {
+        tree
+    };
+
+    let base = path
+        .file_name()
+        .ok_or_else(|| ZipError::InvalidPath(format!("Path {} ended in ..", path)))?;
+
+    parent_dir
+        .get(base)
+        .ok_or_else(|| ZipError::NoSuchFile(path.to_owned()))
 }
 
 /// Places the given entry in the given directory tree.
 fn entree_entry<'a>(
     entry: &'a FileMetadata<'a>,
     tree: &mut DirectoryContents<'a>,
+    options: &TreeOptions,
 ) -> ZipResult<()> {
     let path = &entry.path;
 
@@ -434,19 +1123,50 @@ fn entree_entry<'a>(
     };
 
     // Check: Path doesn't end in something weird.
-    let _base = path
+    let base = path
         .file_name()
         .ok_or_else(|| ZipError::Hierarchy(format!("Path {path} ended in ..")))?;
 
-    let to_insert: DirectoryEntry = if entry.is_dir() {
-        DirectoryEntry::Directory(Directory::new(entry))
-    } else {
-        DirectoryEntry::File(entry)
+    let existing = match parent_dir.entry(base) {
+        btree_map::Entry::Vacant(vacancy) => {
+            let to_insert: DirectoryEntry = if entry.is_dir() {
+                DirectoryEntry::Directory(Directory::new(entry))
+            } else {
+                DirectoryEntry::File(FileEntry::new(entry))
+            };
+            vacancy.insert(to_insert);
+            return Ok(());
+        }
+        btree_map::Entry::Occupied(occupied) => occupied.into_mut(),
     };
 
-    if parent_dir.insert(to_insert.name(), to_insert).is_some() {
+    if options.duplicates == DuplicatePolicy::Error {
         return Err(ZipError::Hierarchy(format!("Duplicate entry for {path}",)));
     }
+    // Swapping a directory for a file (or vice versa) would orphan children
+    // or turn a file into something it isn't. Don't guess.
+    if existing.metadata().is_dir() != entry.is_dir() {
+        return Err(ZipError::Hierarchy(format!(
+            "Duplicate entry for {path} is both a file and a directory",
+        )));
+    }
+    // Duplicate directories keep their children either way;
+    // we're just picking which metadata to use.
+    match options.duplicates {
+        DuplicatePolicy::Error => unreachable!(),
+        DuplicatePolicy::FirstWins => {
+            warn!("Duplicate entry for {path}; keeping the first one");
+        }
+        DuplicatePolicy::LastWins => {
+            warn!("Duplicate entry for {path}; keeping the last one");
+            existing.replace_metadata(entry);
+        }
+        DuplicatePolicy::KeepAll => {
+            debug!("Duplicate entry for {path}; keeping all versions");
+            let previous = existing.replace_metadata(entry);
+            existing.earlier_versions_mut().push(previous);
+        }
+    }
 
     Ok(())
 }
@@ -476,11 +1196,8 @@ fn walk_parent_directories_mut<'a, 'b>(
                 // Huh. Keep going.
             }
             Utf8Component::ParentDir => {
-                // We could canonicalize it somewhere down the road.
-                // Path::canonicalize() doesn't work because it tries
-                // to actually resolve the path
-                // (and failing if something doesn't exist there).
-                // Maybe try https://crates.io/crates/path-clean some time?
+                // Users who want these resolved instead of rejected
+                // can run the entries through sanitize_entries() first.
                 return Err(ZipError::Hierarchy(format!(
                     "Parent dir (..) found in path {path}",
                 )));
@@ -509,8 +1226,9 @@ fn walk_parent_directories_mut<'a, 'b>(
     Ok(current)
 }
 
-/// Used by `FileTree::get()` to walk the tree to the parent directory
-/// where the desired file lives.
+/// Used by `FileTree::lookup()` to walk the tree to the parent directory
+/// where the desired file lives, and by `FileTree::subtree()`
+/// to walk to the directory itself.
 ///
 /// Consequently, this assumes that `path` is provided by the user,
 /// and emits errors accordingly.
@@ -628,7 +1346,7 @@ impl<'a> Iterator for FileTreeIterator<'a, '_> {
         let next = self.inner.stack.last_mut().unwrap().next();
         match next {
             Some(DirectoryEntry::File(f)) => {
-                return Some(f);
+                return Some(f.metadata);
             }
             Some(DirectoryEntry::Directory(d)) => {
                 self.inner.stack.push(d.children.values());
@@ -677,3 +1395,323 @@ impl<'a, 'b> Iterator for DirectoryTreeIterator<'a, 'b> {
         self.next()
     }
 }
+
+/// What [`FileTree::filter()`] should do with an entry
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Visit {
+    /// Yield the entry, and if it's a directory, visit its children.
+    Yield,
+    /// Don't yield the entry, but if it's a directory, visit its children.
+    Skip,
+    /// Yield the entry, but don't visit any of its children.
+    YieldAndPrune,
+    /// Don't yield the entry or visit any of its children.
+    Prune,
+}
+
+impl Visit {
+    fn yields(self) -> bool {
+        matches!(self, Visit::Yield | Visit::YieldAndPrune)
+    }
+
+    fn descends(self) -> bool {
+        matches!(self, Visit::Yield | Visit::Skip)
+    }
+}
+
+/// Decides which entries [`FileTree::filter()`] yields and descends into.
+///
+/// Implemented for [`Glob`]s and `FnMut(&DirectoryEntry) -> Visit` closures.
+pub trait TreeFilter<'a> {
+    fn visit(&mut self, entry: &DirectoryEntry<'a>) -> Visit;
+}
+
+impl<'a, F: FnMut(&DirectoryEntry<'a>) -> Visit> TreeFilter<'a> for F {
+    fn visit(&mut self, entry: &DirectoryEntry<'a>) -> Visit {
+        self(entry)
+    }
+}
+
+/// Iterates over the entries in a [`FileTree`] that pass a [`TreeFilter`]
+///
+/// [`FileTree`]: struct.FileTree.html
+pub struct FilteredTreeIterator<'a, 'b, F> {
+    stack: Vec<btree_map::Values<'b, &'a str, DirectoryEntry<'a>>>,
+    filter: F,
+}
+
+impl<'a, 'b, F> FilteredTreeIterator<'a, 'b, F> {
+    fn new(tree: &'b DirectoryContents<'a>, filter: F) -> Self {
+        let stack = vec![tree.values()];
+        Self { stack, filter }
+    }
+}
+
+impl<'a, 'b, F: TreeFilter<'a>> Iterator for FilteredTreeIterator<'a, 'b, F> {
+    type Item = &'b DirectoryEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Loop instead of recursing like the other iterators,
+        // since a filter can skip arbitrarily many entries in a row.
+        loop {
+            let entry = match self.stack.last_mut()?.next() {
+                Some(entry) => entry,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            let visit = self.filter.visit(entry);
+            if let DirectoryEntry::Directory(d) = entry {
+                if visit.descends() {
+                    self.stack.push(d.children.values());
+                }
+            }
+            if visit.yields() {
+                return Some(entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    pub(crate) fn metadata(path: &'static str, header_offset: usize) -> FileMetadata<'static> {
+        FileMetadata {
+            size: if path.ends_with('/') { 0 } else { 1 },
+            compressed_size: 1,
+            compression_method: CompressionMethod::None,
+            crc32: 0,
+            encrypted: false,
+            path: Cow::Borrowed(Utf8Path::new(path)),
+            last_modified: NaiveDateTime::default(),
+            unix_mode: None,
+            header_offset,
+            index: 0,
+            sanitized: false,
+        }
+    }
+
+    #[test]
+    fn one_shot_reads() -> ZipResult<()> {
+        use flate2::write::DeflateEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let contents = b"Hello, hello, hello, is there anybody in there?".repeat(100);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&contents)?;
+        let compressed = encoder.finish()?;
+        let mut entry = FileMetadata {
+            size: contents.len(),
+            compressed_size: compressed.len(),
+            compression_method: CompressionMethod::Deflate,
+            crc32: crc32fast::hash(&contents),
+            ..metadata("hello", 0)
+        };
+
+        let mut buf = vec![0; contents.len()];
+        decompress_into(&entry, &compressed, &mut buf, &Decoders::new())?;
+        assert!(buf == contents);
+
+        // Truncated data, wrong sizes, and wrong CRCs all fail.
+        assert!(decompress_into(
+            &entry,
+            &compressed[..compressed.len() / 2],
+            &mut buf,
+            &Decoders::new()
+        )
+        .is_err());
+        entry.crc32 ^= 1;
+        assert!(decompress_into(&entry, &compressed, &mut buf, &Decoders::new()).is_err());
+        entry.crc32 ^= 1;
+        for size in [contents.len() - 1, contents.len() + 1] {
+            entry.size = size;
+            let mut buf = vec![0; size];
+            assert!(matches!(
+                decompress_into(&entry, &compressed, &mut buf, &Decoders::new()),
+                Err(ZipError::InvalidArchive(_))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn declared_sizes() -> ZipResult<()> {
+        use flate2::write::DeflateEncoder;
+        use flate2::Compression;
+        use std::io::{Read, Write};
+
+        let contents = b"All work and no play makes Jack a dull boy. ".repeat(1000);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&contents)?;
+        let compressed = encoder.finish()?;
+        let mut entry = FileMetadata {
+            size: contents.len(),
+            compressed_size: compressed.len(),
+            compression_method: CompressionMethod::Deflate,
+            crc32: crc32fast::hash(&contents),
+            ..metadata("shining", 0)
+        };
+        let read_all = |entry: &FileMetadata, data: &[u8]| -> ZipResult<Vec<u8>> {
+            let mut out = Vec::new();
+            make_reader(entry, data, &Decoders::new())?.read_to_end(&mut out)?;
+            Ok(out)
+        };
+        assert!(read_all(&entry, &compressed)? == contents);
+
+        // Readers stop as soon as they pass the declared size,
+        // instead of decompressing the whole thing and failing the CRC.
+        entry.size = 10;
+        let mut reader = make_reader(&entry, &compressed[..], &Decoders::new())?;
+        let mut buf = vec![0; contents.len()];
+        let mut total = 0;
+        let err = loop {
+            match reader.read(&mut buf) {
+                Ok(n) => total += n,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(total, 0);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Files shorter than their declared size fail too.
+        entry.size = contents.len() + 1;
+        assert!(matches!(
+            read_all(&entry, &compressed),
+            Err(e) if matches!(e.kind(), ZipError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof)
+        ));
+        entry.size = contents.len();
+
+        // And so does junk after the end of the Deflate stream,
+        // whether streaming, all at once, or seeking.
+        let mut padded = compressed.clone();
+        padded.extend_from_slice(b"junk");
+        entry.compressed_size = padded.len();
+        assert!(matches!(
+            read_all(&entry, &padded),
+            Err(e) if matches!(e.kind(), ZipError::Io(e) if e.kind() == io::ErrorKind::InvalidData)
+        ));
+        let mut buf = vec![0; contents.len()];
+        assert!(decompress_into(&entry, &padded, &mut buf, &Decoders::new()).is_err());
+        let mut seekable = SeekableReader::new(&entry, Box::new(padded))?;
+        assert!(seekable.read_to_end(&mut Vec::new()).is_err());
+
+        // We don't allocate room for files that couldn't be that big,
+        let impossible = FileMetadata {
+            size: entry.compressed_size * MAX_DEFLATE_RATIO + 1,
+            ..entry.clone()
+        };
+        assert!(file_buffer(&impossible, &Decoders::new()).is_err());
+        // or that we can't decompress.
+        let unsupported = FileMetadata {
+            compression_method: CompressionMethod::Unsupported(42),
+            ..entry
+        };
+        assert!(matches!(
+            file_buffer(&unsupported, &Decoders::new()),
+            Err(ZipError::UnsupportedMethod(42))
+        ));
+        Ok(())
+    }
+
+    fn appended() -> Vec<FileMetadata<'static>> {
+        vec![
+            metadata("dir/", 0),
+            metadata("dir/a", 1),
+            metadata("dir/", 2),
+            metadata("dir/b", 3),
+            metadata("dir/a", 4),
+        ]
+    }
+
+    #[test]
+    fn duplicates_error_by_default() {
+        let entries = appended();
+        match as_tree(&entries) {
+            Err(ZipError::Hierarchy(_)) => {}
+            other => panic!("Expected a hierarchy error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn duplicate_policies() -> ZipResult<()> {
+        let entries = appended();
+
+        let first = TreeOptions::new().duplicates(DuplicatePolicy::FirstWins);
+        let tree = as_tree_with(&entries, &first)?;
+        assert_eq!(tree.lookup("dir")?.header_offset, 0);
+        assert_eq!(tree.lookup("dir/a")?.header_offset, 1);
+        assert_eq!(tree.lookup("dir/b")?.header_offset, 3);
+
+        let last = TreeOptions::new().duplicates(DuplicatePolicy::LastWins);
+        let tree = as_tree_with(&entries, &last)?;
+        assert_eq!(tree.lookup("dir")?.header_offset, 2);
+        assert_eq!(tree.lookup("dir/a")?.header_offset, 4);
+        assert_eq!(tree.lookup("dir/b")?.header_offset, 3);
+        assert_eq!(tree.lookup_versions("dir/a")?.len(), 1);
+
+        let all = TreeOptions::new().duplicates(DuplicatePolicy::KeepAll);
+        let tree = as_tree_with(&entries, &all)?;
+        assert_eq!(tree.lookup("dir/a")?.header_offset, 4);
+        let offsets = |path| -> ZipResult<Vec<usize>> {
+            Ok(tree
+                .lookup_versions(path)?
+                .iter()
+                .map(|v| v.header_offset)
+                .collect())
+        };
+        assert_eq!(offsets("dir")?, [0, 2]);
+        assert_eq!(offsets("dir/a")?, [1, 4]);
+        assert_eq!(offsets("dir/b")?, [3]);
+        assert_eq!(tree.files().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn subtrees() -> ZipResult<()> {
+        let entries = vec![
+            metadata("a/", 0),
+            metadata("a/b/", 1),
+            metadata("a/b/c", 2),
+            metadata("a/d", 3),
+            metadata("e", 4),
+        ];
+        let tree = as_tree(&entries)?;
+
+        assert!(matches!(
+            tree.lookup_entry("a/b")?,
+            DirectoryEntry::Directory(_)
+        ));
+        assert!(matches!(tree.lookup_entry("a/d")?, DirectoryEntry::File(_)));
+
+        let a = tree.subtree("a")?;
+        assert_eq!(a.lookup("b/c")?.header_offset, 2);
+        assert_eq!(a.files().count(), 2);
+
+        let listing = |path| -> ZipResult<Vec<usize>> {
+            Ok(tree
+                .list_dir(path)?
+                .map(|e| e.metadata().header_offset)
+                .collect())
+        };
+        assert_eq!(listing("")?, [0, 4]);
+        assert_eq!(listing("a")?, [1, 3]);
+        assert_eq!(listing("a/b/")?, [2]);
+        assert!(matches!(listing("a/d"), Err(ZipError::InvalidPath(_))));
+        assert!(matches!(listing("a/nope"), Err(ZipError::NoSuchFile(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_file_and_directory() {
+        let entries = vec![metadata("a", 0), metadata("a/", 1)];
+        let last = TreeOptions::new().duplicates(DuplicatePolicy::LastWins);
+        assert!(matches!(
+            as_tree_with(&entries, &last),
+            Err(ZipError::Hierarchy(_))
+        ));
+    }
+}
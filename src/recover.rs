@@ -0,0 +1,242 @@
+//! Salvaging what we can from damaged archives, like `zip -FF`
+//!
+//! Everything about a ZIP archive hangs off its central directory,
+//! at the very end of the file. Truncate an upload or mangle its last few bytes,
+//! and there's nothing to go on... except that each file's data is also preceded
+//! by a local file header with (nearly) the same information.
+//! So scan the archive front to back for those, and find each file's data
+//! from the sizes in its header, or from the data descriptor after it.
+
+use std::collections::{HashMap, HashSet};
+
+use log::*;
+
+use crate::arch::usize;
+use crate::read::FileMetadata;
+use crate::result::*;
+use crate::spec;
+
+/// The results of [`ZipArchive::recover()`]
+///
+/// [`ZipArchive::recover()`]: struct.ZipArchive.html#method.recover
+#[derive(Debug)]
+pub struct RecoveryReport {
+    /// The number of entries salvaged
+    pub recovered: usize,
+    /// Everything that looked like a local file header but couldn't be salvaged,
+    /// in archive order
+    pub failures: Vec<RecoveryFailure>,
+}
+
+impl RecoveryReport {
+    /// Returns true if every local file header we found was salvaged.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A local file header (or something that looked like one)
+/// that [`ZipArchive::recover()`] couldn't salvage, and why
+///
+/// [`ZipArchive::recover()`]: struct.ZipArchive.html#method.recover
+#[derive(Debug)]
+pub struct RecoveryFailure {
+    /// Where the header starts
+    pub offset: usize,
+    pub error: ZipError,
+}
+
+/// Scans `mapping` for local file headers, returning an entry for each
+/// whose data we can find.
+pub(crate) fn recover_entries(mapping: &[u8]) -> (Vec<FileMetadata<'_>>, RecoveryReport) {
+    let mut entries = Vec::new();
+    let mut failures = Vec::new();
+    let mut descriptors = None;
+
+    let mut posit = 0;
+    while let Some(found) = mapping.get(posit..).and_then(spec::find_local_file_header) {
+        let header_offset = posit + found;
+        match recover_entry(mapping, header_offset, entries.len(), &mut descriptors) {
+            Ok((entry, end)) => {
+                debug!("Recovered {:?}", entry);
+                entries.push(entry);
+                // Skip its data so we don't go looking for headers in there.
+                posit = end;
+            }
+            Err(error) => {
+                let error = error.at(header_offset);
+                warn!("Couldn't recover an entry: {}", error);
+                failures.push(RecoveryFailure {
+                    offset: header_offset,
+                    error,
+                });
+                // It could have been a fluke; keep looking past its magic number.
+                posit = header_offset + 1;
+            }
+        }
+    }
+
+    info!(
+        "Recovered {} entries ({} failures)",
+        entries.len(),
+        failures.len()
+    );
+    let report = RecoveryReport {
+        recovered: entries.len(),
+        failures,
+    };
+    (entries, report)
+}
+
+/// Where streamed files' data descriptors are, keyed by where their data starts,
+/// as the descriptor and the offset just past its end
+type Descriptors = HashMap<usize, (spec::DataDescriptor, usize)>;
+
+/// Recovers the entry whose local file header is at `header_offset`,
+/// returning it and the offset just past its data (and data descriptor, if any).
+///
+/// `descriptors` are found the first time a streamed file needs one.
+fn recover_entry<'a>(
+    mapping: &'a [u8],
+    header_offset: usize,
+    index: usize,
+    descriptors: &mut Option<Descriptors>,
+) -> ZipResult<(FileMetadata<'a>, usize)> {
+    let mut rest = &mapping[header_offset..];
+    let local_header = spec::LocalFileHeader::parse_and_consume(&mut rest)?;
+    trace!("{:?}", local_header);
+    let mut entry = FileMetadata::from_lone_local_header(&local_header, header_offset, index)?;
+    let data_start = mapping.len() - rest.len();
+
+    if spec::has_data_descriptor(local_header.flags) {
+        let (descriptor, descriptor_end) = descriptors
+            .get_or_insert_with(|| find_descriptors(mapping))
+            .get(&data_start)
+            .ok_or(ZipError::InvalidArchive(
+                "Couldn't find the file's data descriptor",
+            ))?;
+        trace!("{:?}", descriptor);
+        entry.crc32 = descriptor.crc32;
+        entry.size = usize(descriptor.uncompressed_size)?;
+        entry.compressed_size = usize(descriptor.compressed_size)?;
+        return Ok((entry, *descriptor_end));
+    }
+
+    let end = data_start
+        .checked_add(entry.compressed_size)
+        .filter(|end| *end <= mapping.len())
+        .ok_or(ZipError::InvalidArchive(
+            "File data runs past the end of the archive",
+        ))?;
+    Ok((entry, end))
+}
+
+/// Finds the data descriptor of every streamed file in `mapping`.
+///
+/// Searching from each file's data to the end of the archive would take
+/// quadratic time when descriptors are missing (say, in a truncated upload
+/// full of streamed files), so find the start of every streamed file's data,
+/// then match them all up with descriptors in a single pass.
+fn find_descriptors(mapping: &[u8]) -> Descriptors {
+    let mut data_starts = HashSet::new();
+    let mut posit = 0;
+    while let Some(found) = mapping.get(posit..).and_then(spec::find_local_file_header) {
+        let header_offset = posit + found;
+        let mut rest = &mapping[header_offset..];
+        if let Ok(header) = spec::LocalFileHeader::parse_and_consume(&mut rest) {
+            if spec::has_data_descriptor(header.flags) {
+                data_starts.insert(mapping.len() - rest.len());
+            }
+        }
+        posit = header_offset + 1;
+    }
+
+    let mut descriptors = Descriptors::new();
+    for (descriptor, descriptor_offset, end) in spec::DataDescriptor::find_all(mapping) {
+        let data_start = usize(descriptor.compressed_size)
+            .ok()
+            .and_then(|size| descriptor_offset.checked_sub(size));
+        if let Some(data_start) = data_start.filter(|start| data_starts.contains(start)) {
+            // The first descriptor after the data wins.
+            descriptors.entry(data_start).or_insert((descriptor, end));
+        }
+    }
+    descriptors
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Makes a stored file's local header and data,
+    /// with the CRC and sizes in a data descriptor if `streamed`.
+    fn stored(path: &str, contents: &[u8], streamed: bool) -> Vec<u8> {
+        let crc32 = crc32fast::hash(contents);
+        let size = contents.len() as u32;
+        let mut file = b"PK\x03\x04".to_vec();
+        file.extend_from_slice(&20u16.to_le_bytes()); // version
+        file.extend_from_slice(&(if streamed { 1u16 << 3 } else { 0 }).to_le_bytes());
+        file.extend_from_slice(&[0; 6]); // method, time, date
+        for field in [crc32, size, size] {
+            file.extend_from_slice(&(if streamed { 0 } else { field }).to_le_bytes());
+        }
+        file.extend_from_slice(&(path.len() as u16).to_le_bytes());
+        file.extend_from_slice(&[0; 2]); // extra field length
+        file.extend_from_slice(path.as_bytes());
+        file.extend_from_slice(contents);
+        if streamed {
+            file.extend_from_slice(b"PK\x07\x08");
+            for field in [crc32, size, size] {
+                file.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        file
+    }
+
+    #[test]
+    fn salvage() {
+        let mut archive = b"Some junk up front".to_vec();
+        archive.extend(stored("a.txt", b"Hello!", false));
+        // Magic numbers in the data shouldn't throw us off.
+        archive.extend(stored("b.txt", b"PK\x03\x04PK\x07\x08", true));
+        let truncated = stored("c.txt", b"Never gonna make it", false);
+        archive.extend_from_slice(&truncated[..truncated.len() - 1]);
+
+        let (entries, report) = recover_entries(&archive);
+        let paths: Vec<_> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "b.txt"]);
+        assert_eq!(report.recovered, 2);
+        assert_eq!(entries[0].header_offset, 18);
+        assert_eq!(entries[1].size, 8);
+        assert_eq!(entries[1].crc32, crc32fast::hash(b"PK\x03\x04PK\x07\x08"));
+        assert_eq!(entries[1].index, 1);
+
+        assert_eq!(report.failures.len(), 1);
+        let failure = &report.failures[0];
+        assert_eq!(failure.offset, archive.len() - truncated.len() + 1);
+        assert!(matches!(failure.error.kind(), ZipError::InvalidArchive(_)));
+
+        // A streamed file whose descriptor got cut off can't be salvaged.
+        let streamed = stored("d.txt", b"Gone", true);
+        let (entries, report) = recover_entries(&streamed[..streamed.len() - 4]);
+        assert!(entries.is_empty());
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn missing_descriptors() {
+        // Lots of streamed files without their descriptors shouldn't take forever
+        // (i.e., searching the rest of the archive for each one's descriptor).
+        let streamed = stored("e.txt", b"", true);
+        let headerless = &streamed[..streamed.len() - 16];
+        let mut archive = headerless.repeat(20_000);
+        // And one at the end that's still intact
+        archive.extend(stored("f.txt", b"Made it", true));
+
+        let (entries, report) = recover_entries(&archive);
+        assert_eq!(report.failures.len(), 20_000);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path.as_str(), "f.txt");
+        assert_eq!(entries[0].size, 7);
+    }
+}
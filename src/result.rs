@@ -1,15 +1,20 @@
 //! Error types and the related `Result<T>`
 
+use std::fmt;
+use std::io;
+
 use camino::Utf8PathBuf;
 use thiserror::Error;
 
+use crate::read::FileMetadata;
+
 pub type ZipResult<T> = Result<T, ZipError>;
 
 #[derive(Debug, Error)]
 pub enum ZipError {
     /// An error from underlying I/O
-    #[error("I/O Error")]
-    Io(#[from] std::io::Error),
+    #[error("I/O error: {0}")]
+    Io(io::Error),
 
     /// The ZIP archive contained invalid data per the spec.
     #[error("Invalid Zip archive: {0}")]
@@ -23,6 +28,33 @@ pub enum ZipError {
     #[error("Unsupported Zip archive: {0}")]
     UnsupportedArchive(String),
 
+    /// A file is compressed with a method piz doesn't support,
+    /// and no [`Decoder`] was registered for it.
+    ///
+    /// [`Decoder`]: ../read/trait.Decoder.html
+    #[error("Unsupported compression method {0}")]
+    UnsupportedMethod(u16),
+
+    /// A file is encrypted, and decryption is unsupported.
+    #[error("Encrypted files are unsupported")]
+    Encrypted,
+
+    /// The ZIP archive spans multiple disks (or claims to),
+    /// which is unsupported.
+    #[error("Multi-disk archives are unsupported: {0}")]
+    MultiDisk(String),
+
+    /// A file's CRC-32 didn't match the one the archive gave for it.
+    #[error("Checksum mismatch: expected {expected:#010x}, got {actual:#010x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+
+    /// Raw access to a file's contents (e.g., [`stored_bytes()`]) was asked for,
+    /// but the file is compressed, not stored.
+    ///
+    /// [`stored_bytes()`]: ../read/struct.ZipArchive.html#method.stored_bytes
+    #[error("File is compressed, not stored")]
+    NotStored,
+
     /// The ZIP archive is prepended some unknown bytes.
     /// (Use [`ZipArchive::with_prepended_data()`] if this is okay.)
     ///
@@ -50,4 +82,217 @@ pub enum ZipError {
     /// file streams instead of a memory map to work with large files in 32 bits.
     #[error("Zip archive too large for address space")]
     InsufficientAddressSpace,
+
+    /// The ZIP archive (or reading it) went past a configured limit.
+    /// (See [`Limits`].)
+    ///
+    /// [`Limits`]: ../read/struct.Limits.html
+    #[error("Zip archive exceeds a limit: {0}")]
+    LimitExceeded(String),
+
+    /// One of the errors above, and where in the archive it happened
+    ///
+    /// Use [`kind()`](Self::kind) to match on the error itself.
+    #[error("{context}: {error}")]
+    InArchive {
+        context: ErrorContext,
+        error: Box<ZipError>,
+    },
+}
+
+/// Where in a ZIP archive an error happened, as best we know
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The byte offset (from the start of the data given to piz)
+    /// of the record where things went wrong,
+    /// like a central directory entry or a local file header
+    pub offset: Option<u64>,
+    /// The index of the entry involved, in central directory order
+    /// (i.e., into [`ZipArchive::entries()`])
+    ///
+    /// [`ZipArchive::entries()`]: ../read/struct.ZipArchive.html#method.entries
+    pub entry: Option<usize>,
+    /// The path of the entry involved
+    pub path: Option<Utf8PathBuf>,
+}
+
+impl ErrorContext {
+    /// Returns the context of the given entry.
+    pub(crate) fn entry(metadata: &FileMetadata) -> Self {
+        Self {
+            offset: None,
+            entry: Some(metadata.index),
+            path: Some(metadata.path.to_path_buf()),
+        }
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(entry) = self.entry {
+            parts.push(format!("entry {entry}"));
+        }
+        if let Some(path) = &self.path {
+            parts.push(format!("({path})"));
+        }
+        if let Some(offset) = self.offset {
+            parts.push(format!("at offset {offset}"));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl ZipError {
+    /// Returns the error itself, without any [`ErrorContext`], for matching on.
+    ///
+    /// ```no_run
+    /// # use std::fs;
+    /// # use piz::*;
+    /// # use piz::read::*;
+    /// # use piz::result::*;
+    /// # let bytes = fs::read("upload.zip")?;
+    /// # let archive = ZipArchive::new(&bytes)?;
+    /// # let entry = &archive.entries()[0];
+    /// match archive.read_to_vec(entry) {
+    ///     Ok(contents) => { /* ... */ }
+    ///     Err(e) => match e.kind() {
+    ///         ZipError::ChecksumMismatch { .. } => eprintln!("Corrupt upload: {e}"),
+    ///         ZipError::Encrypted | ZipError::UnsupportedMethod(_) => eprintln!("Can't read: {e}"),
+    ///         _ => return Err(e.into()),
+    ///     },
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn kind(&self) -> &ZipError {
+        match self {
+            ZipError::InArchive { error, .. } => error.kind(),
+            e => e,
+        }
+    }
+
+    /// Returns where in the archive the error happened, if we know.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            ZipError::InArchive { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Notes the offset of the record where the error happened,
+    /// unless we already know a more precise one.
+    pub(crate) fn at(self, offset: usize) -> Self {
+        self.with_context(|context| {
+            context.offset.get_or_insert(offset as u64);
+        })
+    }
+
+    /// Notes the index of the entry involved.
+    pub(crate) fn for_index(self, index: usize) -> Self {
+        self.with_context(|context| {
+            context.entry.get_or_insert(index);
+        })
+    }
+
+    /// Notes the entry involved.
+    pub(crate) fn for_entry(self, metadata: &FileMetadata) -> Self {
+        self.within(&ErrorContext::entry(metadata))
+    }
+
+    /// Notes whatever we didn't already know from the given context.
+    pub(crate) fn within(self, outer: &ErrorContext) -> Self {
+        self.with_context(|context| {
+            if context.offset.is_none() {
+                context.offset = outer.offset;
+            }
+            if context.entry.is_none() {
+                context.entry = outer.entry;
+            }
+            if context.path.is_none() {
+                context.path.clone_from(&outer.path);
+            }
+        })
+    }
+
+    fn with_context(self, update: impl FnOnce(&mut ErrorContext)) -> Self {
+        let (mut context, error) = match self {
+            ZipError::InArchive { context, error } => (context, error),
+            e => (ErrorContext::default(), Box::new(e)),
+        };
+        update(&mut context);
+        ZipError::InArchive { context, error }
+    }
+}
+
+/// Unwraps `ZipError`s that passed through an `io::Error`
+/// (say, a checksum mismatch from a reader).
+impl From<io::Error> for ZipError {
+    fn from(e: io::Error) -> Self {
+        e.downcast::<ZipError>().unwrap_or_else(ZipError::Io)
+    }
+}
+
+/// Lets readers return `ZipError`s, which `ZipError::from()` can get back.
+impl From<ZipError> for io::Error {
+    fn from(e: ZipError) -> Self {
+        let kind = match e.kind() {
+            ZipError::Io(io) => io.kind(),
+            ZipError::InvalidArchive(_)
+            | ZipError::Encoding(_)
+            | ZipError::ChecksumMismatch { .. } => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::Other,
+        };
+        match e {
+            ZipError::Io(io) => io,
+            e => io::Error::new(kind, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn context() {
+        let e = ZipError::ChecksumMismatch {
+            expected: 1,
+            actual: 2,
+        }
+        .for_index(3)
+        .at(100)
+        // Don't overwrite what we already know.
+        .at(42);
+        assert_eq!(
+            e.context(),
+            Some(&ErrorContext {
+                offset: Some(100),
+                entry: Some(3),
+                path: None,
+            })
+        );
+        assert_eq!(
+            e.to_string(),
+            "entry 3 at offset 100: Checksum mismatch: expected 0x00000001, got 0x00000002"
+        );
+
+        // Errors make it through readers intact.
+        let through_io = ZipError::from(io::Error::from(e));
+        assert!(matches!(
+            through_io.kind(),
+            ZipError::ChecksumMismatch {
+                expected: 1,
+                actual: 2
+            }
+        ));
+        assert_eq!(through_io.context().unwrap().entry, Some(3));
+
+        // And plain I/O errors keep their kind (and say what they are).
+        let eof = ZipError::Io(io::ErrorKind::UnexpectedEof.into()).at(7);
+        let eof = io::Error::from(eof);
+        assert_eq!(eof.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(ZipError::from(eof)
+            .to_string()
+            .contains("unexpected end of file"));
+    }
 }
@@ -0,0 +1,304 @@
+//! Cleans up paths from untrusted archives instead of rejecting them.
+//!
+//! [`as_tree()`] refuses paths with `..`, prefixes, and the like,
+//! which is the right call if you just want to know something is fishy.
+//! If you're processing uploads from the wild, you probably want to normalize
+//! those paths into something safe to extract and keep going.
+//!
+//! [`as_tree()`]: ../read/fn.as_tree.html
+
+use std::borrow::Cow;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use log::*;
+
+use crate::read::FileMetadata;
+use crate::result::*;
+
+/// A change [`sanitize_entries()`] made to an entry's path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathFix {
+    /// Backslashes were treated as path separators.
+    ConvertedBackslashes,
+    /// A drive letter (like `C:`) was removed from the front of the path.
+    RemovedDriveLetter(String),
+    /// Leading slashes were removed, making the path relative.
+    RemovedRoot,
+    /// An empty component (as in `a//b`) was removed.
+    RemovedEmptyComponent,
+    /// A current directory component (`.`) was removed.
+    RemovedCurrentDir,
+    /// A parent directory component (`..`) was resolved
+    /// by removing it and the component before it.
+    ResolvedParentDir,
+}
+
+/// How [`sanitize_entries()`] rewrote the path of an entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathRewrite {
+    /// The path as stored in the archive
+    pub original: Utf8PathBuf,
+    /// The cleaned-up path, or `None` if the entry was dropped
+    /// because it was a directory entry for the root of the archive (e.g., `./`).
+    pub sanitized: Option<Utf8PathBuf>,
+    /// Every fix that was applied, in order
+    pub fixes: Vec<PathFix>,
+}
+
+/// Given metadata from [`ZipArchive::entries()`],
+/// returns copies with normalized paths that are safe to extract,
+/// along with a report of every entry that was rewritten.
+///
+/// This:
+///
+/// - Treats backslashes as path separators
+/// - Strips leading slashes and drive letters
+/// - Removes empty and current directory (`.`) components
+/// - Resolves `..` components, so long as they don't climb out of the archive
+///
+/// and fails with a [`ZipError::Hierarchy`] error if any path:
+///
+/// - Contains a NUL byte
+/// - Uses `..` to escape the root of the archive
+/// - Has a component that's a reserved name on Windows (`CON`, `NUL`, `COM1`, `CONIN$`, ...)
+///
+/// Feed the results to [`as_tree()`] or [`as_tree_with()`] as usual.
+/// Since different paths can clean up to the same one,
+/// consider using a [`DuplicatePolicy`] besides `Error`.
+///
+/// ```no_run
+/// # use std::fs;
+/// # use piz::*;
+/// # use piz::read::*;
+/// let bytes = fs::read("upload.zip")?;
+/// let archive = ZipArchive::new(&bytes)?;
+/// let (entries, rewrites) = sanitize_entries(archive.entries())?;
+/// for rewrite in &rewrites {
+///     println!("{} -> {:?}: {:?}", rewrite.original, rewrite.sanitized, rewrite.fixes);
+/// }
+/// let tree = as_tree(&entries)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`ZipArchive::entries()`]: ../read/struct.ZipArchive.html#method.entries
+/// [`as_tree()`]: ../read/fn.as_tree.html
+/// [`as_tree_with()`]: ../read/fn.as_tree_with.html
+/// [`DuplicatePolicy`]: ../read/enum.DuplicatePolicy.html
+pub fn sanitize_entries<'a>(
+    entries: &[FileMetadata<'a>],
+) -> ZipResult<(Vec<FileMetadata<'a>>, Vec<PathRewrite>)> {
+    let mut sanitized_entries = Vec::with_capacity(entries.len());
+    let mut rewrites = Vec::new();
+
+    for entry in entries {
+        let (sanitized, fixes) = sanitize_path(&entry.path)?;
+        if fixes.is_empty() {
+            sanitized_entries.push(entry.clone());
+            continue;
+        }
+        debug!("Sanitized {} to {:?}: {:?}", entry.path, sanitized, fixes);
+
+        match &sanitized {
+            Some(path) => sanitized_entries.push(FileMetadata {
+                path: Cow::Owned(path.clone()),
+                sanitized: true,
+                ..entry.clone()
+            }),
+            None if entry.is_dir() => {
+                warn!("Dropping entry {}, which is the archive's root", entry.path);
+            }
+            None => {
+                return Err(ZipError::Hierarchy(format!(
+                    "File {} has no name once sanitized",
+                    entry.path
+                )));
+            }
+        }
+        rewrites.push(PathRewrite {
+            original: entry.path.clone().into_owned(),
+            sanitized,
+            fixes,
+        });
+    }
+
+    Ok((sanitized_entries, rewrites))
+}
+
+/// Cleans up a single path, per [`sanitize_entries()`].
+///
+/// Returns `None` for the path if nothing's left (e.g., `./`).
+/// Directories keep their trailing slash.
+pub(crate) fn sanitize_path(path: &Utf8Path) -> ZipResult<(Option<Utf8PathBuf>, Vec<PathFix>)> {
+    let mut fixes = Vec::new();
+    let mut remaining: Cow<str> = Cow::Borrowed(path.as_str());
+
+    if remaining.contains('\0') {
+        return Err(ZipError::Hierarchy(format!(
+            "NUL byte found in path {path:?}"
+        )));
+    }
+
+    if remaining.contains('\\') {
+        fixes.push(PathFix::ConvertedBackslashes);
+        remaining = Cow::Owned(remaining.replace('\\', "/"));
+    }
+
+    // camino only knows about prefixes when built for Windows,
+    // so look for drive letters ourselves.
+    let bytes = remaining.as_bytes();
+    if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        fixes.push(PathFix::RemovedDriveLetter(remaining[..2].to_owned()));
+        remaining = Cow::Owned(remaining[2..].to_owned());
+    }
+
+    let relative = remaining.trim_start_matches('/');
+    if relative.len() != remaining.len() {
+        fixes.push(PathFix::RemovedRoot);
+    }
+
+    let is_dir = relative.ends_with('/');
+    let relative = relative.strip_suffix('/').unwrap_or(relative);
+
+    let mut components: Vec<&str> = Vec::new();
+    if !relative.is_empty() {
+        for component in relative.split('/') {
+            match component {
+                "" => fixes.push(PathFix::RemovedEmptyComponent),
+                "." => fixes.push(PathFix::RemovedCurrentDir),
+                ".." => {
+                    if components.pop().is_none() {
+                        return Err(ZipError::Hierarchy(format!(
+                            "Parent dir (..) escapes the archive in path {path}"
+                        )));
+                    }
+                    fixes.push(PathFix::ResolvedParentDir);
+                }
+                normal => {
+                    if is_windows_reserved(normal) {
+                        return Err(ZipError::Hierarchy(format!(
+                            "Reserved Windows name {normal} found in path {path}"
+                        )));
+                    }
+                    components.push(normal);
+                }
+            }
+        }
+    }
+
+    if components.is_empty() {
+        return Ok((None, fixes));
+    }
+
+    let mut sanitized = components.join("/");
+    if is_dir {
+        sanitized.push('/');
+    }
+    Ok((Some(sanitized.into()), fixes))
+}
+
+/// Returns true if Windows would treat the given file name as a device.
+///
+/// Windows strips trailing dots and spaces from names, and ignores extensions
+/// (and spaces before them) here, so `nul.txt`, `CON `, and `aux.` are just as
+/// reserved as `NUL`, `CON`, and `AUX`.
+fn is_windows_reserved(name: &str) -> bool {
+    const RESERVED: [&str; 6] = ["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"];
+    const NUMBERED: [&str; 2] = ["COM", "LPT"];
+    // Windows counts superscript digits as digits, too.
+    const SUPERSCRIPTS: [&str; 3] = ["¹", "²", "³"];
+
+    let name = name.trim_end_matches(['.', ' ']);
+    let stem = name.split('.').next().unwrap_or(name).trim_end_matches(' ');
+    if RESERVED.iter().any(|r| stem.eq_ignore_ascii_case(r)) {
+        return true;
+    }
+    if !stem.is_char_boundary(3) {
+        return false;
+    }
+    let (device, number) = stem.split_at(3);
+    NUMBERED.iter().any(|n| device.eq_ignore_ascii_case(n))
+        && (matches!(number.as_bytes(), [b'1'..=b'9']) || SUPERSCRIPTS.contains(&number))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sanitized(path: &str) -> ZipResult<(Option<String>, Vec<PathFix>)> {
+        let (path, fixes) = sanitize_path(Utf8Path::new(path))?;
+        Ok((path.map(Utf8PathBuf::into_string), fixes))
+    }
+
+    #[test]
+    fn clean_paths_are_untouched() -> ZipResult<()> {
+        assert_eq!(sanitized("a/b/c.txt")?, (Some("a/b/c.txt".into()), vec![]));
+        assert_eq!(sanitized("a/b/")?, (Some("a/b/".into()), vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn rewrites() -> ZipResult<()> {
+        assert_eq!(
+            sanitized("/etc/passwd")?,
+            (Some("etc/passwd".into()), vec![PathFix::RemovedRoot])
+        );
+        assert_eq!(
+            sanitized("C:\\Windows\\.\\win.ini")?,
+            (
+                Some("Windows/win.ini".into()),
+                vec![
+                    PathFix::ConvertedBackslashes,
+                    PathFix::RemovedDriveLetter("C:".into()),
+                    PathFix::RemovedRoot,
+                    PathFix::RemovedCurrentDir,
+                ]
+            )
+        );
+        assert_eq!(
+            sanitized("a//b/../c/")?,
+            (
+                Some("a/c/".into()),
+                vec![PathFix::RemovedEmptyComponent, PathFix::ResolvedParentDir]
+            )
+        );
+        assert_eq!(sanitized("./")?, (None, vec![PathFix::RemovedCurrentDir]));
+        Ok(())
+    }
+
+    #[test]
+    fn rejections() {
+        for bad in [
+            "../evil",
+            "a/../../evil",
+            "a\0b",
+            "dir/CON",
+            "nul.txt",
+            "LPT9 .log",
+            "con.",
+            "aux ",
+            "Prn. .",
+            "conin$",
+            "CONOUT$.txt",
+            "dir/COM¹",
+            "lpt³.dat",
+        ] {
+            assert!(
+                matches!(sanitized(bad), Err(ZipError::Hierarchy(_))),
+                "{bad:?} wasn't rejected"
+            );
+        }
+        // Close, but not reserved
+        for fine in [
+            "CONSOLE",
+            "COM0",
+            "LPT10",
+            "nullable.rs",
+            ".con",
+            "CONIN",
+            "COM⁴",
+            "é",
+        ] {
+            assert!(sanitized(fine).is_ok(), "{fine:?} was rejected");
+        }
+    }
+}
@@ -0,0 +1,601 @@
+//! Readers that can seek around inside a file
+//!
+//! Stored files are just a slice of the archive, so seeking in them is free.
+//! Deflate streams can't be entered in the middle, though,
+//! so seeking backwards means inflating again from the start,
+//! skipping output until we get where we're going.
+//! The last 32 KiB of output (Deflate's window) are always kept around,
+//! so short hops backwards are cheap.
+//!
+//! For long jumps around big files, a [`SeekIndex`] records checkpoints
+//! (à la zlib's `zran.c`) that decompression can resume from:
+//! the position of a Deflate block boundary,
+//! plus the 32 KiB of output before it that the next blocks can refer back to.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+use crc32fast::Hasher;
+use miniz_oxide::inflate::core::inflate_flags::TINFL_FLAG_STOP_ON_BLOCK_BOUNDARY;
+use miniz_oxide::inflate::core::{
+    decompress, BlockBoundaryState, DecompressorOxide, TINFL_LZ_DICT_SIZE,
+};
+use miniz_oxide::inflate::TINFLStatus;
+
+use crate::read::{CompressionMethod, FileMetadata};
+use crate::result::*;
+
+/// The size of Deflate's window, and of our ring buffer of recent output
+const WINDOW_SIZE: usize = TINFL_LZ_DICT_SIZE;
+
+/// A reader for a file in a ZIP archive that implements [`Seek`]
+///
+/// Returned by [`ZipArchive::read_seekable()`].
+/// Seeking in stored files is free; seeking backwards in compressed ones
+/// (more than 32 KiB, anyways) decompresses again from the start of the file.
+///
+/// Like other readers, the file's CRC32 is checked when reading hits the end,
+/// provided all of it was decompressed along the way.
+/// So is the Deflate stream, which must end there, at the end of the compressed data.
+///
+/// [`ZipArchive::read_seekable()`]: struct.ZipArchive.html#method.read_seekable
+pub struct SeekableReader<'a> {
+    /// The file's (compressed) data
+    data: Box<dyn AsRef<[u8]> + Send + 'a>,
+    /// The file's uncompressed size
+    size: u64,
+    position: u64,
+    /// Decompression state, or `None` for stored files
+    inflater: Option<Box<Inflater>>,
+    checksum: Checksum,
+    /// Which entry we're reading, for errors
+    context: ErrorContext,
+}
+
+impl<'a> SeekableReader<'a> {
+    pub(crate) fn new(
+        metadata: &FileMetadata,
+        data: Box<dyn AsRef<[u8]> + Send + 'a>,
+    ) -> ZipResult<Self> {
+        let size = metadata.size as u64;
+        let inflater = match metadata.compression_method {
+            CompressionMethod::None => {
+                if (*data).as_ref().len() != metadata.size {
+                    return Err(ZipError::InvalidArchive(
+                        "Stored file's size doesn't match its compressed size",
+                    ));
+                }
+                None
+            }
+            CompressionMethod::Deflate => Some(Box::new(Inflater::new(size))),
+            CompressionMethod::Unsupported(method) => {
+                return Err(ZipError::UnsupportedMethod(method))
+            }
+        };
+        Ok(Self {
+            data,
+            size,
+            position: 0,
+            inflater,
+            checksum: Checksum {
+                hasher: Hasher::new(),
+                hashed: 0,
+                expected: metadata.crc32,
+            },
+            context: ErrorContext::entry(metadata),
+        })
+    }
+
+    /// Returns the file's uncompressed size.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Decompresses the whole file, recording a checkpoint roughly every
+    /// `spacing` bytes of output that later reads can resume from.
+    ///
+    /// Smaller spacing makes seeks faster, but each checkpoint costs 32 KiB.
+    /// (zlib's `zran.c` suggests a megabyte or so.)
+    /// The file's CRC32 is checked along the way.
+    /// Stored files don't need an index, so get an empty one.
+    ///
+    /// ```no_run
+    /// # use std::fs::{self, File};
+    /// # use std::io::{Seek, SeekFrom};
+    /// # use std::sync::Arc;
+    /// # use piz::*;
+    /// # use piz::read::*;
+    /// let bytes = fs::read("logs.zip")?;
+    /// let archive = ZipArchive::new(&bytes)?;
+    /// let tree = as_tree(archive.entries())?;
+    /// let log = tree.lookup("huge.log")?;
+    ///
+    /// let index = archive.read_seekable(log)?.build_index(1024 * 1024)?;
+    /// index.write_to(&mut File::create("huge.log.index")?)?;
+    ///
+    /// // Later...
+    /// let index = SeekIndex::read_from(&mut File::open("huge.log.index")?)?;
+    /// let mut reader = archive.read_seekable(log)?.with_index(Arc::new(index))?;
+    /// reader.seek(SeekFrom::Start(1_500_000_000))?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn build_index(&mut self, spacing: u64) -> ZipResult<SeekIndex> {
+        let mut index = SeekIndex {
+            crc32: self.checksum.expected,
+            size: self.size,
+            compressed_size: (*self.data).as_ref().len() as u64,
+            checkpoints: Vec::new(),
+        };
+        let Some(inflater) = &mut self.inflater else {
+            return Ok(index);
+        };
+
+        let data = (*self.data).as_ref();
+        let mut checksum = Checksum {
+            hasher: Hasher::new(),
+            hashed: 0,
+            expected: self.checksum.expected,
+        };
+        let mut recorder = Recorder {
+            spacing: spacing.max(1),
+            checkpoints: &mut index.checkpoints,
+        };
+        inflater.restart();
+        while !inflater.done {
+            inflater
+                .inflate_more(data, &mut checksum, Some(&mut recorder))
+                .map_err(|e| ZipError::from(e).within(&self.context))?;
+        }
+        if inflater.produced != self.size {
+            return Err(ZipError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Deflate stream ended before the file's declared size",
+            ))
+            .within(&self.context));
+        }
+        checksum.check().map_err(|e| e.within(&self.context))?;
+        Ok(index)
+    }
+
+    /// Uses the given index (from [`build_index()`](Self::build_index))
+    /// to jump close to wherever we seek, instead of decompressing from the start.
+    ///
+    /// Fails if the index was built for a different file.
+    pub fn with_index(mut self, index: Arc<SeekIndex>) -> ZipResult<Self> {
+        if index.crc32 != self.checksum.expected
+            || index.size != self.size
+            || index.compressed_size != (*self.data).as_ref().len() as u64
+        {
+            return Err(ZipError::InvalidArchive(
+                "Seek index was built for a different file",
+            ));
+        }
+        if let Some(inflater) = &mut self.inflater {
+            inflater.index = Some(index);
+        }
+        Ok(self)
+    }
+}
+
+impl Read for SeekableReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let data = (*self.data).as_ref();
+        if self.position >= self.size {
+            if self.checksum.hashed == self.size {
+                // Make sure the stream ends here too, and nothing comes after it,
+                // like Crc32Reader does.
+                if let Some(inflater) = &mut self.inflater {
+                    inflater
+                        .finish(data, &mut self.checksum)
+                        .map_err(|e| io::Error::from(ZipError::from(e).within(&self.context)))?;
+                }
+                self.checksum.check().map_err(|e| e.within(&self.context))?;
+            }
+            return Ok(0);
+        }
+
+        let count = match &mut self.inflater {
+            None => {
+                // new() made sure size == data.len(), so this is in bounds.
+                let remaining = &data[self.position as usize..];
+                let count = buf.len().min(remaining.len());
+                buf[..count].copy_from_slice(&remaining[..count]);
+                self.checksum.update(self.position, &buf[..count]);
+                count
+            }
+            Some(inflater) => inflater
+                .read_at(data, self.position, buf, &mut self.checksum)
+                .map_err(|e| io::Error::from(ZipError::from(e).within(&self.context)))?,
+        };
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for SeekableReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // Like files, seeking past the end is fine; reads there just return nothing.
+        // Decompression happens lazily on the next read.
+        self.position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.position)
+    }
+}
+
+/// Inflates a Deflate stream into a ring buffer of its last 32 KiB of output
+struct Inflater {
+    decompressor: DecompressorOxide,
+    /// The last `WINDOW_SIZE` bytes of output, where output byte `n`
+    /// lives at `window[n % WINDOW_SIZE]`
+    window: [u8; WINDOW_SIZE],
+    /// Compressed bytes consumed so far
+    consumed: usize,
+    /// Uncompressed bytes produced so far
+    produced: u64,
+    /// The expected uncompressed size
+    size: u64,
+    done: bool,
+    index: Option<Arc<SeekIndex>>,
+}
+
+impl Inflater {
+    fn new(size: u64) -> Self {
+        Self {
+            decompressor: DecompressorOxide::new(),
+            window: [0; WINDOW_SIZE],
+            consumed: 0,
+            produced: 0,
+            size,
+            done: false,
+            index: None,
+        }
+    }
+
+    /// Starts over from the beginning of the stream.
+    fn restart(&mut self) {
+        self.decompressor.init();
+        self.consumed = 0;
+        self.produced = 0;
+        self.done = false;
+    }
+
+    /// Picks up decompression from the given checkpoint.
+    fn resume_from(&mut self, checkpoint: &Checkpoint) {
+        self.decompressor = DecompressorOxide::from_block_boundary_state(&BlockBoundaryState {
+            num_bits: checkpoint.num_bits,
+            bit_buf: checkpoint.bit_buf,
+            ..Default::default()
+        });
+        // Put the saved window back in the ring where it'd be had we gotten here normally.
+        let window_start = checkpoint.output - checkpoint.window.len() as u64;
+        for (i, b) in checkpoint.window.iter().enumerate() {
+            self.window[((window_start + i as u64) % WINDOW_SIZE as u64) as usize] = *b;
+        }
+        self.consumed = checkpoint.input as usize;
+        self.produced = checkpoint.output;
+        self.done = false;
+    }
+
+    /// Reads output starting at `position` (which must be less than `size`),
+    /// decompressing whatever it takes to get there.
+    fn read_at(
+        &mut self,
+        data: &[u8],
+        position: u64,
+        buf: &mut [u8],
+        checksum: &mut Checksum,
+    ) -> io::Result<usize> {
+        loop {
+            let behind = position < self.produced.saturating_sub(WINDOW_SIZE as u64);
+            if !behind && position < self.produced {
+                let start = (position % WINDOW_SIZE as u64) as usize;
+                // Copy up to the end of the ring (or the output); the next read can wrap.
+                let available = ((self.produced - position) as usize).min(WINDOW_SIZE - start);
+                let count = buf.len().min(available);
+                buf[..count].copy_from_slice(&self.window[start..start + count]);
+                return Ok(count);
+            }
+
+            // Jump to a checkpoint if it gets us closer than where we are now.
+            let index = self.index.clone();
+            let checkpoint = index
+                .as_deref()
+                .and_then(|index| index.nearest(position))
+                .filter(|c| behind || c.output > self.produced);
+            match checkpoint {
+                Some(checkpoint) => self.resume_from(checkpoint),
+                // It's fallen out of the window; go back to the start.
+                None if behind => self.restart(),
+                None => self.inflate_more(data, checksum, None)?,
+            }
+        }
+    }
+
+    /// Decompresses whatever's left once all `size` bytes have been produced,
+    /// failing if the stream has more output, or stops short of the end of `data`.
+    fn finish(&mut self, data: &[u8], checksum: &mut Checksum) -> io::Result<()> {
+        while !self.done {
+            self.inflate_more(data, checksum, None)?;
+        }
+        Ok(())
+    }
+
+    /// Decompresses up to the end of the ring buffer,
+    /// or to the end of a block if we're recording checkpoints.
+    fn inflate_more(
+        &mut self,
+        data: &[u8],
+        checksum: &mut Checksum,
+        recorder: Option<&mut Recorder>,
+    ) -> io::Result<()> {
+        if self.done {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Deflate stream ended before the file's declared size",
+            ));
+        }
+        let out_pos = (self.produced % WINDOW_SIZE as u64) as usize;
+        // No other flags: a raw Deflate stream, all of it in `data`,
+        // and a wrapping output buffer (our ring).
+        let flags = if recorder.is_some() {
+            TINFL_FLAG_STOP_ON_BLOCK_BOUNDARY
+        } else {
+            0
+        };
+        let (status, read, written) = decompress(
+            &mut self.decompressor,
+            &data[self.consumed..],
+            &mut self.window,
+            out_pos,
+            flags,
+        );
+        checksum.update(self.produced, &self.window[out_pos..out_pos + written]);
+        self.consumed += read;
+        self.produced += written as u64;
+
+        if self.produced > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Deflate stream is longer than the file's declared size",
+            ));
+        }
+        match status {
+            TINFLStatus::HasMoreOutput => Ok(()),
+            TINFLStatus::BlockBoundary => {
+                if let Some(recorder) = recorder {
+                    recorder.block_boundary(self);
+                }
+                Ok(())
+            }
+            TINFLStatus::Done if self.consumed != data.len() => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Deflate stream ends before the file's compressed data does",
+            )),
+            TINFLStatus::Done => {
+                self.done = true;
+                Ok(())
+            }
+            TINFLStatus::FailedCannotMakeProgress | TINFLStatus::NeedsMoreInput => Err(
+                io::Error::new(io::ErrorKind::UnexpectedEof, "Deflate stream is truncated"),
+            ),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Corrupt Deflate stream",
+            )),
+        }
+    }
+}
+
+/// Collects checkpoints while building a [`SeekIndex`]
+struct Recorder<'c> {
+    spacing: u64,
+    checkpoints: &'c mut Vec<Checkpoint>,
+}
+
+impl Recorder<'_> {
+    fn block_boundary(&mut self, inflater: &Inflater) {
+        let last = self.checkpoints.last().map_or(0, |c| c.output);
+        if inflater.produced - last < self.spacing {
+            return;
+        }
+        let Some(state) = inflater.decompressor.block_boundary_state() else {
+            return;
+        };
+        // Save the window in order, oldest byte first.
+        let window_len = inflater.produced.min(WINDOW_SIZE as u64);
+        let window = (inflater.produced - window_len..inflater.produced)
+            .map(|n| inflater.window[(n % WINDOW_SIZE as u64) as usize])
+            .collect();
+        self.checkpoints.push(Checkpoint {
+            input: inflater.consumed as u64,
+            output: inflater.produced,
+            num_bits: state.num_bits,
+            bit_buf: state.bit_buf,
+            window,
+        });
+    }
+}
+
+/// Checkpoints for jumping into the middle of a compressed file,
+/// from [`SeekableReader::build_index()`]
+///
+/// Indexes can be saved with [`write_to()`](Self::write_to)
+/// and loaded with [`read_from()`](Self::read_from),
+/// so that only the first pass over a file has to decompress all of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeekIndex {
+    /// The file this index was built for, so we can make sure it's used with the same one
+    crc32: u32,
+    size: u64,
+    compressed_size: u64,
+    /// In order of increasing offsets
+    checkpoints: Vec<Checkpoint>,
+}
+
+/// A place decompression can resume from
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Checkpoint {
+    /// The offset in the compressed data, just past the byte holding `num_bits`
+    input: u64,
+    /// The offset in the uncompressed data
+    output: u64,
+    /// The bits left over from the last byte before `input`, from BlockBoundaryState
+    num_bits: u8,
+    bit_buf: u8,
+    /// The (up to) 32 KiB of output before this point
+    window: Vec<u8>,
+}
+
+/// Identifies serialized [`SeekIndex`]es, and their format version
+const INDEX_MAGIC: &[u8; 8] = b"pizidx\0\x01";
+
+impl SeekIndex {
+    /// Returns the number of checkpoints in the index.
+    pub fn len(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    /// Returns true if the index has no checkpoints.
+    pub fn is_empty(&self) -> bool {
+        self.checkpoints.is_empty()
+    }
+
+    /// Returns the last checkpoint at or before the given offset.
+    fn nearest(&self, position: u64) -> Option<&Checkpoint> {
+        let after = self.checkpoints.partition_point(|c| c.output <= position);
+        after.checked_sub(1).map(|i| &self.checkpoints[i])
+    }
+
+    /// Writes the index in a compact binary format.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(INDEX_MAGIC)?;
+        writer.write_all(&self.crc32.to_le_bytes())?;
+        writer.write_all(&self.size.to_le_bytes())?;
+        writer.write_all(&self.compressed_size.to_le_bytes())?;
+        writer.write_all(&(self.checkpoints.len() as u64).to_le_bytes())?;
+        for checkpoint in &self.checkpoints {
+            writer.write_all(&checkpoint.input.to_le_bytes())?;
+            writer.write_all(&checkpoint.output.to_le_bytes())?;
+            writer.write_all(&[checkpoint.num_bits, checkpoint.bit_buf])?;
+            writer.write_all(&(checkpoint.window.len() as u32).to_le_bytes())?;
+            writer.write_all(&checkpoint.window)?;
+        }
+        Ok(())
+    }
+
+    /// Reads an index written by [`write_to()`](Self::write_to).
+    ///
+    /// The index is sanity-checked, but make sure it came from somewhere you trust,
+    /// since a doctored one could make readers return the wrong data.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let invalid = |why| io::Error::new(io::ErrorKind::InvalidData, why);
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            return Err(invalid("Not a piz seek index"));
+        }
+        let crc32 = read_u32(reader)?;
+        let size = read_u64(reader)?;
+        let compressed_size = read_u64(reader)?;
+        let count = read_u64(reader)?;
+
+        // Don't trust count for preallocation.
+        let mut checkpoints: Vec<Checkpoint> = Vec::new();
+        for _ in 0..count {
+            let input = read_u64(reader)?;
+            let output = read_u64(reader)?;
+            let mut bits = [0; 2];
+            reader.read_exact(&mut bits)?;
+            let window_len = read_u32(reader)? as u64;
+
+            let (last_input, last_output) =
+                checkpoints.last().map_or((0, 0), |c| (c.input, c.output));
+            if input <= last_input
+                || input > compressed_size
+                || output <= last_output
+                || output > size
+                || bits[0] > 7
+                || window_len != output.min(WINDOW_SIZE as u64)
+            {
+                return Err(invalid("Corrupt seek index"));
+            }
+            let mut window = vec![0; window_len as usize];
+            reader.read_exact(&mut window)?;
+            checkpoints.push(Checkpoint {
+                input,
+                output,
+                num_bits: bits[0],
+                bit_buf: bits[1],
+                window,
+            });
+        }
+        Ok(Self {
+            crc32,
+            size,
+            compressed_size,
+            checkpoints,
+        })
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Hashes a file's contents as they're first read or decompressed, in order
+struct Checksum {
+    hasher: Hasher,
+    /// The number of bytes (from the start of the file) hashed so far
+    hashed: u64,
+    expected: u32,
+}
+
+impl Checksum {
+    /// Hashes whatever part of `bytes`, which start at `offset` in the file,
+    /// we haven't seen yet.
+    fn update(&mut self, offset: u64, bytes: &[u8]) {
+        let end = offset + bytes.len() as u64;
+        if offset <= self.hashed && self.hashed < end {
+            self.hasher
+                .update(&bytes[(self.hashed - offset) as usize..]);
+            self.hashed = end;
+        }
+    }
+
+    /// Fails if the checksum so far isn't the expected one.
+    fn check(&self) -> ZipResult<()> {
+        let actual = self.hasher.clone().finalize();
+        if actual != self.expected {
+            return Err(ZipError::ChecksumMismatch {
+                expected: self.expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
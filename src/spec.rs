@@ -14,7 +14,6 @@
 //! [_Zip Files: History, Explanation and Implementation_]: https://www.hanshq.net/zip.html
 
 use std::borrow::Cow;
-use std::convert::TryInto;
 
 use camino::Utf8Path;
 use chrono::{NaiveDate, NaiveDateTime};
@@ -24,6 +23,7 @@ use memchr::memmem;
 use crate::arch::usize;
 use crate::read::{CompressionMethod, FileMetadata};
 use crate::result::*;
+use crate::sanitize::sanitize_path;
 
 // Magic numbers denoting various sections of a ZIP archive
 
@@ -37,6 +37,8 @@ const ZIP64_EOCDR_LOCATOR_MAGIC: [u8; 4] = [b'P', b'K', 6, 7];
 const CENTRAL_DIRECTORY_MAGIC: [u8; 4] = [b'P', b'K', 1, 2];
 /// Local file header magic number
 const LOCAL_FILE_HEADER_MAGIC: [u8; 4] = [b'P', b'K', 3, 4];
+/// Data descriptor magic number (which is optional, but nearly everyone writes it)
+const DATA_DESCRIPTOR_MAGIC: [u8; 4] = [b'P', b'K', 7, 8];
 
 impl CompressionMethod {
     fn from_u16(u: u16) -> Self {
@@ -90,27 +92,35 @@ impl System {
     }
 }
 
-// Straight from the Rust docs:
+/// Splits `len` bytes off the front of the provided slice, shrinking it.
+fn take<'a>(input: &mut &'a [u8], len: usize) -> ZipResult<&'a [u8]> {
+    if input.len() < len {
+        return Err(ZipError::InvalidArchive("Unexpected end of record"));
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
 
 /// Reads a little-endian u64 from the front of the provided slice, shrinking it.
-fn read_u64(input: &mut &[u8]) -> u64 {
-    let (int_bytes, rest) = input.split_at(std::mem::size_of::<u64>());
-    *input = rest;
-    u64::from_le_bytes(int_bytes.try_into().expect("less than eight bytes for u64"))
+fn read_u64(input: &mut &[u8]) -> ZipResult<u64> {
+    let mut int_bytes = [0; 8];
+    int_bytes.copy_from_slice(take(input, 8)?);
+    Ok(u64::from_le_bytes(int_bytes))
 }
 
 /// Reads a little-endian u32 from the front of the provided slice, shrinking it.
-fn read_u32(input: &mut &[u8]) -> u32 {
-    let (int_bytes, rest) = input.split_at(std::mem::size_of::<u32>());
-    *input = rest;
-    u32::from_le_bytes(int_bytes.try_into().expect("less than four bytes for u32"))
+fn read_u32(input: &mut &[u8]) -> ZipResult<u32> {
+    let mut int_bytes = [0; 4];
+    int_bytes.copy_from_slice(take(input, 4)?);
+    Ok(u32::from_le_bytes(int_bytes))
 }
 
 /// Reads a little-endian u16 from the front of the provided slice, shrinking it.
-fn read_u16(input: &mut &[u8]) -> u16 {
-    let (int_bytes, rest) = input.split_at(std::mem::size_of::<u16>());
-    *input = rest;
-    u16::from_le_bytes(int_bytes.try_into().expect("less than two bytes for u16"))
+fn read_u16(input: &mut &[u8]) -> ZipResult<u16> {
+    let mut int_bytes = [0; 2];
+    int_bytes.copy_from_slice(take(input, 2)?);
+    Ok(u16::from_le_bytes(int_bytes))
 }
 
 /// Data from the End of central directory record
@@ -148,18 +158,19 @@ impl<'a> EndOfCentralDirectory<'a> {
         // the starting disk number        4 bytes
         // zipfile comment length          2 bytes
 
-        // Assert the magic instead of checking for it
-        // because the search should have found it.
-        assert_eq!(eocdr[..4], EOCDR_MAGIC);
-        eocdr = &eocdr[4..];
-        let disk_number = read_u16(&mut eocdr);
-        let disk_with_central_directory = read_u16(&mut eocdr);
-        let entries_on_this_disk = read_u16(&mut eocdr);
-        let entries = read_u16(&mut eocdr);
-        let central_directory_size = read_u32(&mut eocdr);
-        let central_directory_offset = read_u32(&mut eocdr);
-        let comment_length = read_u16(&mut eocdr);
-        let file_comment = &eocdr[..usize(comment_length)?];
+        if take(&mut eocdr, 4)? != EOCDR_MAGIC {
+            return Err(ZipError::InvalidArchive(
+                "Invalid End Of Central Directory Record",
+            ));
+        }
+        let disk_number = read_u16(&mut eocdr)?;
+        let disk_with_central_directory = read_u16(&mut eocdr)?;
+        let entries_on_this_disk = read_u16(&mut eocdr)?;
+        let entries = read_u16(&mut eocdr)?;
+        let central_directory_size = read_u32(&mut eocdr)?;
+        let central_directory_offset = read_u32(&mut eocdr)?;
+        let comment_length = read_u16(&mut eocdr)?;
+        let file_comment = take(&mut eocdr, usize(comment_length)?)?;
 
         Ok(Self {
             disk_number,
@@ -171,17 +182,95 @@ impl<'a> EndOfCentralDirectory<'a> {
             file_comment,
         })
     }
+
+    pub fn fixed_size_in_file() -> usize {
+        22
+    }
 }
 
-/// Searches backward through `mapping` to find the
+/// Searches backward through the end of `mapping` to find the
 /// End of central directory record.
 ///
-/// It should be right at the end of the file,
+/// It should be right at the end of the file, followed only by the archive's comment,
 /// but its variable size means we can't jump to a known offset.
+/// And since the comment (or a file's data) can contain its magic number,
+/// only go with a candidate whose comment reaches the end of the file
+/// and whose central directory could be where it says, trying the next one back if not.
+/// Failing that, settle for one with some junk after its comment.
 pub fn find_eocdr(mapping: &[u8]) -> ZipResult<usize> {
-    memmem::rfind(mapping, &EOCDR_MAGIC).ok_or(ZipError::InvalidArchive(
-        "Couldn't find End Of Central Directory Record",
-    ))
+    // It can't be further back than its size with the longest possible comment.
+    let max_size = EndOfCentralDirectory::fixed_size_in_file() + u16::MAX as usize;
+    let search_start = mapping.len().saturating_sub(max_size);
+    let candidates = || {
+        memmem::rfind_iter(&mapping[search_start..], &EOCDR_MAGIC).map(|posit| search_start + posit)
+    };
+    candidates()
+        .find(|posit| is_plausible_eocdr(mapping, *posit, true))
+        .or_else(|| candidates().find(|posit| is_plausible_eocdr(mapping, *posit, false)))
+        .ok_or(ZipError::InvalidArchive(
+            "Couldn't find End Of Central Directory Record",
+        ))
+}
+
+/// Returns true if there's an End of central directory record at `posit`
+/// whose comment ends at the end of `mapping` (or anywhere, if not `exact_end`)
+/// and whose central directory's size and offset are consistent with where it is.
+fn is_plausible_eocdr(mapping: &[u8], posit: usize, exact_end: bool) -> bool {
+    let Ok(eocdr) = EndOfCentralDirectory::parse(&mapping[posit..]) else {
+        return false;
+    };
+    let end = posit + EndOfCentralDirectory::fixed_size_in_file() + eocdr.file_comment.len();
+    if exact_end && end != mapping.len() {
+        return false;
+    }
+
+    // Zip64 archives keep the real size and offset in their own records,
+    // starting with a locator right before this one.
+    if let Some(locator_posit) =
+        posit.checked_sub(Zip64EndOfCentralDirectoryLocator::size_in_file())
+    {
+        if let Some(locator) = Zip64EndOfCentralDirectoryLocator::parse(&mapping[locator_posit..]) {
+            return is_plausible_zip64(mapping, locator_posit, &locator);
+        }
+    }
+
+    // Otherwise the central directory sits right before this record,
+    // and prepended data can only push it further along than its offset says.
+    let Some(central_directory) = posit.checked_sub(eocdr.central_directory_size as usize) else {
+        return false;
+    };
+    if eocdr.central_directory_offset as usize > central_directory {
+        return false;
+    }
+    eocdr.entries == 0 || mapping[central_directory..].starts_with(&CENTRAL_DIRECTORY_MAGIC)
+}
+
+/// Returns true if the given Zip64 locator (at `locator_posit`) leads to
+/// a Zip64 End of central directory record before it,
+/// whose central directory ends before that record does.
+fn is_plausible_zip64(
+    mapping: &[u8],
+    locator_posit: usize,
+    locator: &Zip64EndOfCentralDirectoryLocator,
+) -> bool {
+    // Like ZipArchive::with_options(), look for the record
+    // from its nominal offset (prepended data only pushes it further along)
+    // up to the locator.
+    let search_space = usize(locator.zip64_eocdr_offset)
+        .ok()
+        .and_then(|start| mapping.get(start..locator_posit));
+    let Some(search_space) = search_space else {
+        return false;
+    };
+    let Ok(zip64_eocdr) = find_zip64_eocdr(search_space)
+        .and_then(|posit| Zip64EndOfCentralDirectory::parse(&search_space[posit..]))
+    else {
+        return false;
+    };
+    zip64_eocdr
+        .central_directory_offset
+        .checked_add(zip64_eocdr.central_directory_size)
+        .is_some_and(|end| end <= locator.zip64_eocdr_offset)
 }
 
 /// Data from the Zip64 end of central directory locator
@@ -207,13 +296,12 @@ impl Zip64EndOfCentralDirectoryLocator {
         // relative offset of the zip64
         // end of central directory record 8 bytes
         // total number of disks           4 bytes
-        if mapping[..4] != ZIP64_EOCDR_LOCATOR_MAGIC {
+        if take(&mut mapping, 4).ok()? != ZIP64_EOCDR_LOCATOR_MAGIC {
             return None;
         }
-        mapping = &mapping[4..];
-        let disk_with_central_directory = read_u32(&mut mapping);
-        let zip64_eocdr_offset = read_u64(&mut mapping);
-        let disks = read_u32(&mut mapping);
+        let disk_with_central_directory = read_u32(&mut mapping).ok()?;
+        let zip64_eocdr_offset = read_u64(&mut mapping).ok()?;
+        let disks = read_u32(&mut mapping).ok()?;
 
         Some(Self {
             disk_with_central_directory,
@@ -268,19 +356,20 @@ impl<'a> Zip64EndOfCentralDirectory<'a> {
         // the starting disk number        8 bytes
         // zip64 extensible data sector    (variable size)
 
-        // Assert the magic instead of checking for it
-        // because the search should have found it.
-        assert_eq!(eocdr[..4], ZIP64_EOCDR_MAGIC);
-        eocdr = &eocdr[4..];
-        let eocdr_size = read_u64(&mut eocdr);
-        let source_version = read_u16(&mut eocdr);
-        let minimum_extract_version = read_u16(&mut eocdr);
-        let disk_number = read_u32(&mut eocdr);
-        let disk_with_central_directory = read_u32(&mut eocdr);
-        let entries_on_this_disk = read_u64(&mut eocdr);
-        let entries = read_u64(&mut eocdr);
-        let central_directory_size = read_u64(&mut eocdr);
-        let central_directory_offset = read_u64(&mut eocdr);
+        if take(&mut eocdr, 4)? != ZIP64_EOCDR_MAGIC {
+            return Err(ZipError::InvalidArchive(
+                "Invalid Zip64 End Of Central Directory Record",
+            ));
+        }
+        let eocdr_size = read_u64(&mut eocdr)?;
+        let source_version = read_u16(&mut eocdr)?;
+        let minimum_extract_version = read_u16(&mut eocdr)?;
+        let disk_number = read_u32(&mut eocdr)?;
+        let disk_with_central_directory = read_u32(&mut eocdr)?;
+        let entries_on_this_disk = read_u64(&mut eocdr)?;
+        let entries = read_u64(&mut eocdr)?;
+        let central_directory_size = read_u64(&mut eocdr)?;
+        let central_directory_offset = read_u64(&mut eocdr)?;
 
         // 4.3.14.1 The value stored into the "size of zip64 end of central
         // directory record" SHOULD be the size of the remaining
@@ -289,16 +378,12 @@ impl<'a> Zip64EndOfCentralDirectory<'a> {
         // Size = SizeOfFixedFields + SizeOfVariableData - 12.
         // (SizeOfVariableData = Size - SizeOfFixedFields + 12)
 
-        // Check for underflow:
-        let eocdr_size = usize(eocdr_size)?;
-        if (eocdr_size + 12) < Self::fixed_size_in_file() {
-            return Err(ZipError::InvalidArchive(
-                "Invalid extensible data length in Zip64 End Of Central Directory Record",
-            ));
-        }
+        // Check for overflow and underflow:
+        let extensible_data_length = usize(eocdr_size)?
+            .checked_add(12)
+            .and_then(|size| size.checked_sub(Self::fixed_size_in_file()));
         // We should be left with just the extensible data:
-        let extensible_data_length = eocdr_size + 12 - Self::fixed_size_in_file();
-        if eocdr.len() != extensible_data_length {
+        if extensible_data_length != Some(eocdr.len()) {
             return Err(ZipError::InvalidArchive(
                 "Invalid extensible data length in Zip64 End Of Central Directory Record",
             ));
@@ -393,30 +478,28 @@ impl<'a> CentralDirectoryEntry<'a> {
         //   file name (variable size)
         //   extra field (variable size)
         //   file comment (variable size)
-        if entry[..4] != CENTRAL_DIRECTORY_MAGIC {
+        if take(entry, 4)? != CENTRAL_DIRECTORY_MAGIC {
             return Err(ZipError::InvalidArchive("Invalid central directory entry"));
         }
-        *entry = &entry[4..];
-        let source_version = read_u16(entry);
-        let minimum_extract_version = read_u16(entry);
-        let flags = read_u16(entry);
-        let compression_method = read_u16(entry);
-        let last_modified_time = read_u16(entry);
-        let last_modified_date = read_u16(entry);
-        let crc32 = read_u32(entry);
-        let compressed_size = read_u32(entry);
-        let uncompressed_size = read_u32(entry);
-        let path_length = usize(read_u16(entry))?;
-        let extra_field_length = usize(read_u16(entry))?;
-        let file_comment_length = usize(read_u16(entry))?;
-        let disk_number = read_u16(entry);
-        let internal_file_attributes = read_u16(entry);
-        let external_file_attributes = read_u32(entry);
-        let header_offset = read_u32(entry);
-        let (path, remaining) = entry.split_at(path_length);
-        let (extra_field, remaining) = remaining.split_at(extra_field_length);
-        let (file_comment, remaining) = remaining.split_at(file_comment_length);
-        *entry = remaining;
+        let source_version = read_u16(entry)?;
+        let minimum_extract_version = read_u16(entry)?;
+        let flags = read_u16(entry)?;
+        let compression_method = read_u16(entry)?;
+        let last_modified_time = read_u16(entry)?;
+        let last_modified_date = read_u16(entry)?;
+        let crc32 = read_u32(entry)?;
+        let compressed_size = read_u32(entry)?;
+        let uncompressed_size = read_u32(entry)?;
+        let path_length = usize(read_u16(entry)?)?;
+        let extra_field_length = usize(read_u16(entry)?)?;
+        let file_comment_length = usize(read_u16(entry)?)?;
+        let disk_number = read_u16(entry)?;
+        let internal_file_attributes = read_u16(entry)?;
+        let external_file_attributes = read_u32(entry)?;
+        let header_offset = read_u32(entry)?;
+        let path = take(entry, path_length)?;
+        let extra_field = take(entry, extra_field_length)?;
+        let file_comment = take(entry, file_comment_length)?;
 
         Ok(Self {
             source_version,
@@ -437,6 +520,10 @@ impl<'a> CentralDirectoryEntry<'a> {
             file_comment,
         })
     }
+
+    pub fn fixed_size_in_file() -> usize {
+        46
+    }
 }
 
 /// Extracts the "is this text UTF-8?" bit from the 16-bit flags field.
@@ -455,9 +542,18 @@ fn is_encrypted(flags: u16) -> bool {
     flags & 1 != 0
 }
 
+/// Extracts the "are the CRC and sizes after the data?" bit from the 16-bit flags field.
+pub fn has_data_descriptor(flags: u16) -> bool {
+    // Bit 3: If this bit is set, the fields crc-32, compressed
+    //        size and uncompressed size are set to zero in the
+    //        local header. The correct values are put in the
+    //        data descriptor immediately following the compressed data.
+    flags & (1 << 3) != 0
+}
+
 impl<'a> FileMetadata<'a> {
-    /// Extracts `FileMetadata` from a central directory entry
-    pub(crate) fn from_cde(cde: &CentralDirectoryEntry<'a>) -> ZipResult<Self> {
+    /// Extracts `FileMetadata` from the `index`th central directory entry
+    pub(crate) fn from_cde(cde: &CentralDirectoryEntry<'a>, index: usize) -> ZipResult<Self> {
         let is_utf8 = is_utf8(cde.flags);
 
         let path: Cow<Utf8Path> = if is_utf8 {
@@ -473,8 +569,8 @@ impl<'a> FileMetadata<'a> {
         };
 
         if cde.disk_number != 0 {
-            return Err(ZipError::UnsupportedArchive(format!(
-                "No support for multi-disk archives: file {path} claims to be on disk {}",
+            return Err(ZipError::MultiDisk(format!(
+                "file {path} claims to be on disk {}",
                 cde.disk_number,
             )));
         }
@@ -509,6 +605,8 @@ impl<'a> FileMetadata<'a> {
             last_modified: parse_msdos(cde.last_modified_time, cde.last_modified_date),
             unix_mode,
             header_offset: usize(cde.header_offset)?,
+            index,
+            sanitized: false,
         };
 
         parse_extra_field(&mut metadata, cde.extra_field)?;
@@ -540,6 +638,21 @@ impl<'a> FileMetadata<'a> {
             }
         };
 
+        // If the central directory's path was cleaned up,
+        // clean up the local one the same way so that the two can be compared.
+        let path = if cde_header.sanitized {
+            match sanitize_path(&path)?.0 {
+                Some(sanitized) => Cow::Owned(sanitized),
+                None => {
+                    return Err(ZipError::InvalidArchive(
+                        "Central directory entry doesn't match local file header",
+                    ))
+                }
+            }
+        } else {
+            path
+        };
+
         let encrypted = is_encrypted(local.flags);
 
         let compression_method = CompressionMethod::from_u16(local.compression_method);
@@ -557,8 +670,42 @@ impl<'a> FileMetadata<'a> {
 
         parse_extra_field(&mut metadata, local.extra_field)?;
 
+        // Streamed files don't know their CRC and sizes until after their data,
+        // so they're in a data descriptor there instead. Go by the central directory's.
+        if has_data_descriptor(local.flags) {
+            metadata.crc32 = cde_header.crc32;
+            metadata.size = cde_header.size;
+            metadata.compressed_size = cde_header.compressed_size;
+        }
+
         Ok(metadata)
     }
+
+    /// Extracts metadata from a local file header at the given offset,
+    /// for when there's no central directory to go by.
+    ///
+    /// If the file has a data descriptor, its CRC and sizes are zero
+    /// until the caller finds it.
+    pub(crate) fn from_lone_local_header(
+        local: &LocalFileHeader<'a>,
+        header_offset: usize,
+        index: usize,
+    ) -> ZipResult<Self> {
+        let nothing = Self {
+            size: 0,
+            compressed_size: 0,
+            compression_method: CompressionMethod::None,
+            crc32: 0,
+            encrypted: false,
+            path: Cow::Borrowed(Utf8Path::new("")),
+            last_modified: NaiveDateTime::default(),
+            unix_mode: None,
+            header_offset,
+            index,
+            sanitized: false,
+        };
+        Self::from_local_header(local, &nothing)
+    }
 }
 
 fn parse_msdos(time: u16, date: u16) -> NaiveDateTime {
@@ -571,10 +718,17 @@ fn parse_msdos(time: u16, date: u16) -> NaiveDateTime {
     // MSDOS uses years since 1980; Always interpreted as a positive value
     let years = ((0b1111_1110_0000_0000 & date) >> 9) as i32 + 1980;
 
-    NaiveDate::from_ymd_opt(years, months, days)
-        .expect("Couldn't parse DOS y/m/d") // Everything possible should be in range.
-        .and_hms_opt(hours, minutes, seconds)
-        .expect("Couldn't parse DOS h/m/s") // Ditto
+    // Some archivers leave timestamps zeroed out (or otherwise out of range).
+    // A bad timestamp isn't worth rejecting the whole archive over,
+    // so clamp each field into range, making zeroed-out dates the DOS epoch.
+    let months = months.clamp(1, 12);
+    // Not every month has 31 days; back up to the last one that exists.
+    let date = (1..=days.max(1))
+        .rev()
+        .find_map(|d| NaiveDate::from_ymd_opt(years, months, d))
+        .unwrap_or_default();
+    date.and_hms_opt(hours.min(23), minutes.min(59), seconds.min(59))
+        .unwrap_or_default()
 }
 
 /// Parses the "extra fields" found in central directory entries
@@ -593,37 +747,101 @@ fn parse_extra_field(metadata: &mut FileMetadata, mut extra_field: &[u8]) -> Zip
 
     //     Header ID - 2 bytes
     //     Data Size - 2 bytes
-    while !extra_field.is_empty() {
-        let kind = read_u16(&mut extra_field);
-        let field_len = read_u16(&mut extra_field);
+    //
+    // (Some tools, like older versions of Android's zipalign,
+    // pad the extra field with a few zeroes too short to be a header. Skip those.)
+    while extra_field.len() >= 4 {
+        let kind = read_u16(&mut extra_field)?;
+        let field_len = read_u16(&mut extra_field)?;
+        let mut field = take(&mut extra_field, usize(field_len)?)?;
 
-        let mut amount_left = field_len as i16;
         // Zip64 extended information extra field
         if kind == 0x0001 {
             if metadata.size == u32::MAX as usize {
-                metadata.size = usize(read_u64(&mut extra_field))?;
-                amount_left -= 8;
+                metadata.size = usize(read_u64(&mut field)?)?;
             }
             if metadata.compressed_size == u32::MAX as usize {
-                metadata.compressed_size = usize(read_u64(&mut extra_field))?;
-                amount_left -= 8;
+                metadata.compressed_size = usize(read_u64(&mut field)?)?;
             }
             if metadata.header_offset == u32::MAX as usize {
-                metadata.header_offset = usize(read_u64(&mut extra_field))?;
-                amount_left -= 8;
+                metadata.header_offset = usize(read_u64(&mut field)?)?;
             }
             // We already checked many times that this isn't a multi-disk archive.
-            if amount_left != 0 {
+            if !field.is_empty() {
                 return Err(ZipError::InvalidArchive(
                     "Extra data field contains disk number",
                 ));
             }
         }
-        extra_field = &extra_field[amount_left as usize..];
     }
     Ok(())
 }
 
+/// Finds the next local file header in `mapping`, if there is one.
+///
+/// Without a central directory to point us at them, this is all we've got.
+pub fn find_local_file_header(mapping: &[u8]) -> Option<usize> {
+    memmem::find(mapping, &LOCAL_FILE_HEADER_MAGIC)
+}
+
+/// Data from a data descriptor
+///
+/// Files written as a stream (where the writer couldn't seek back
+/// and fill in the local file header) have their CRC and sizes here,
+/// right after their compressed data.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DataDescriptor {
+    pub crc32: u32,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+}
+
+impl DataDescriptor {
+    /// Finds everything in `data` that could be a data descriptor,
+    /// returning each with its offset and the offset just past its end.
+    ///
+    /// Since descriptors don't say where they are (or if they're the Zip64 form),
+    /// this is every magic number that's followed by enough bytes for either form,
+    /// in order. A descriptor belongs to the file whose compressed data starts
+    /// its compressed size before it.
+    pub fn find_all(data: &[u8]) -> impl Iterator<Item = (Self, usize, usize)> + '_ {
+        // 4.3.9  Data descriptor:
+        //
+        // [signature                       4 bytes  (0x08074b50)]
+        // crc-32                           4 bytes
+        // compressed size                  4 bytes (8 for Zip64)
+        // uncompressed size                4 bytes (8 for Zip64)
+        //
+        // The signature is optional, but without it there's nothing to look for.
+        memmem::find_iter(data, &DATA_DESCRIPTOR_MAGIC).flat_map(move |posit| {
+            let parse = |zip64: bool| {
+                let mut descriptor = data.get(posit + 4..)?;
+                let before = descriptor.len();
+                let crc32 = read_u32(&mut descriptor).ok()?;
+                let (compressed_size, uncompressed_size) = if zip64 {
+                    (
+                        read_u64(&mut descriptor).ok()?,
+                        read_u64(&mut descriptor).ok()?,
+                    )
+                } else {
+                    (
+                        read_u32(&mut descriptor).ok()? as u64,
+                        read_u32(&mut descriptor).ok()? as u64,
+                    )
+                };
+                let end = posit + 4 + before - descriptor.len();
+                let descriptor = Self {
+                    crc32,
+                    compressed_size,
+                    uncompressed_size,
+                };
+                Some((descriptor, posit, end))
+            };
+            [parse(false), parse(true)].into_iter().flatten()
+        })
+    }
+}
+
 /// Data from a local file header
 ///
 /// Each files' actual contents is preceded by this header.
@@ -663,21 +881,21 @@ impl<'a> LocalFileHeader<'a> {
         //
         // file name (variable size)
         // extra field (variable size)
-        assert_eq!(header[..4], LOCAL_FILE_HEADER_MAGIC);
-        *header = &header[4..];
-        let minimum_extract_version = read_u16(header);
-        let flags = read_u16(header);
-        let compression_method = read_u16(header);
-        let last_modified_time = read_u16(header);
-        let last_modified_date = read_u16(header);
-        let crc32 = read_u32(header);
-        let compressed_size = read_u32(header);
-        let uncompressed_size = read_u32(header);
-        let path_length = usize(read_u16(header))?;
-        let extra_field_length = usize(read_u16(header))?;
-        let (path, remaining) = header.split_at(path_length);
-        let (extra_field, remaining) = remaining.split_at(extra_field_length);
-        *header = remaining;
+        if take(header, 4)? != LOCAL_FILE_HEADER_MAGIC {
+            return Err(ZipError::InvalidArchive("Invalid local file header"));
+        }
+        let minimum_extract_version = read_u16(header)?;
+        let flags = read_u16(header)?;
+        let compression_method = read_u16(header)?;
+        let last_modified_time = read_u16(header)?;
+        let last_modified_date = read_u16(header)?;
+        let crc32 = read_u32(header)?;
+        let compressed_size = read_u32(header)?;
+        let uncompressed_size = read_u32(header)?;
+        let path_length = usize(read_u16(header)?)?;
+        let extra_field_length = usize(read_u16(header)?)?;
+        let path = take(header, path_length)?;
+        let extra_field = take(header, extra_field_length)?;
 
         Ok(Self {
             minimum_extract_version,
@@ -693,3 +911,139 @@ impl<'a> LocalFileHeader<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A central directory entry for "hi.txt", with a Zip64 extra field
+    fn central_directory_entry() -> Vec<u8> {
+        let mut entry = CENTRAL_DIRECTORY_MAGIC.to_vec();
+        entry.extend_from_slice(&[0; 8]); // versions, flags, method
+        entry.extend_from_slice(&0x0000u16.to_le_bytes()); // time
+        entry.extend_from_slice(&0x5021u16.to_le_bytes()); // date: 2020-01-01
+        entry.extend_from_slice(&[0; 4]); // CRC
+        entry.extend_from_slice(&u32::MAX.to_le_bytes()); // compressed size
+        entry.extend_from_slice(&u32::MAX.to_le_bytes()); // size
+        entry.extend_from_slice(&6u16.to_le_bytes()); // path length
+        entry.extend_from_slice(&20u16.to_le_bytes()); // extra field length
+        entry.extend_from_slice(&[0; 10]); // comment length, disk, attributes
+        entry.extend_from_slice(&0u32.to_le_bytes()); // header offset
+        entry.extend_from_slice(b"hi.txt");
+        entry.extend_from_slice(&1u16.to_le_bytes());
+        entry.extend_from_slice(&16u16.to_le_bytes());
+        entry.extend_from_slice(&(5u64 << 32).to_le_bytes());
+        entry.extend_from_slice(&(4u64 << 32).to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn truncated_records() -> ZipResult<()> {
+        let entry = central_directory_entry();
+        let mut slice = &entry[..];
+        let cde = CentralDirectoryEntry::parse_and_consume(&mut slice)?;
+        assert!(slice.is_empty());
+        let parsed = FileMetadata::from_cde(&cde, 0)?;
+        assert_eq!(parsed.size, 5 << 32);
+        assert_eq!(parsed.compressed_size, 4 << 32);
+
+        // Every prefix of a record should fail cleanly, not panic.
+        for len in 0..entry.len() {
+            let result = CentralDirectoryEntry::parse_and_consume(&mut &entry[..len])
+                .and_then(|cde| FileMetadata::from_cde(&cde, 0));
+            assert!(matches!(result, Err(ZipError::InvalidArchive(_))), "{len}");
+        }
+        let mut eocdr = EOCDR_MAGIC.to_vec();
+        eocdr.resize(22, 0);
+        EndOfCentralDirectory::parse(&eocdr)?;
+        for len in 0..eocdr.len() {
+            assert!(EndOfCentralDirectory::parse(&eocdr[..len]).is_err());
+        }
+        assert!(Zip64EndOfCentralDirectory::parse(&ZIP64_EOCDR_MAGIC).is_err());
+        assert!(Zip64EndOfCentralDirectoryLocator::parse(&ZIP64_EOCDR_LOCATOR_MAGIC).is_none());
+        assert!(LocalFileHeader::parse_and_consume(&mut &LOCAL_FILE_HEADER_MAGIC[..]).is_err());
+
+        // And so should wrong magic numbers.
+        assert!(matches!(
+            LocalFileHeader::parse_and_consume(&mut &entry[..]),
+            Err(ZipError::InvalidArchive(_))
+        ));
+        Ok(())
+    }
+
+    /// An End of central directory record for an empty archive
+    fn empty_eocdr(comment: &[u8]) -> Vec<u8> {
+        let mut eocdr = EOCDR_MAGIC.to_vec();
+        eocdr.resize(20, 0);
+        eocdr.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+        eocdr.extend_from_slice(comment);
+        eocdr
+    }
+
+    #[test]
+    fn eocdr_search() {
+        let archive = empty_eocdr(b"");
+        assert_eq!(find_eocdr(&archive).unwrap(), 0);
+
+        // A comment containing the magic number (or a whole record)
+        // shouldn't be mistaken for the real thing,
+        let mut fake = empty_eocdr(b"");
+        fake[12..16].copy_from_slice(&100u32.to_le_bytes()); // central directory size
+        let mut comment = b"PK\x05\x06 marks the end, like so: ".to_vec();
+        comment.extend_from_slice(&fake);
+        let archive = empty_eocdr(&comment);
+        assert_eq!(find_eocdr(&archive).unwrap(), 0);
+
+        // nor should junk after the record, though we put up with it.
+        let mut archive = b"Prepended junk".to_vec();
+        archive.extend(empty_eocdr(b"Hi"));
+        archive.extend_from_slice(b"Appended junk, PK\x05\x06 and all");
+        assert_eq!(find_eocdr(&archive).unwrap(), 14);
+
+        // Central directories need to be where the record says.
+        let mut archive = b"PK\x01\x02 (not really)".to_vec();
+        let mut eocdr = empty_eocdr(b"");
+        eocdr[10..12].copy_from_slice(&1u16.to_le_bytes()); // entries
+        eocdr[12..16].copy_from_slice(&8u32.to_le_bytes()); // central directory size
+        archive.extend(&eocdr);
+        assert!(find_eocdr(&archive).is_err());
+        eocdr[12..16].copy_from_slice(&23u32.to_le_bytes());
+        archive.truncate(23);
+        archive.extend(&eocdr);
+        assert_eq!(find_eocdr(&archive).unwrap(), 23);
+
+        // A Zip64 locator in front of a fake record has to lead somewhere real.
+        let mut comment = b"PK\x06\x07".to_vec();
+        comment.extend_from_slice(&0u32.to_le_bytes()); // disk with the Zip64 EOCDR
+        comment.extend_from_slice(&0u64.to_le_bytes()); // Zip64 EOCDR offset
+        comment.extend_from_slice(&1u32.to_le_bytes()); // disks
+        comment.extend(empty_eocdr(b""));
+        let archive = empty_eocdr(&comment);
+        assert_eq!(find_eocdr(&archive).unwrap(), 0);
+
+        // And the record can't be further back than the longest comment allows.
+        let mut archive = empty_eocdr(b"");
+        archive.resize(22 + u16::MAX as usize + 1, 0);
+        assert!(find_eocdr(&archive).is_err());
+    }
+
+    #[test]
+    fn dos_dates() {
+        assert_eq!(
+            parse_msdos(0x6e5b, 0x5021).to_string(),
+            "2020-01-01 13:50:54"
+        );
+        // Zeroed-out dates are the DOS epoch.
+        assert_eq!(parse_msdos(0, 0).to_string(), "1980-01-01 00:00:00");
+        // Month 0, day 0, February 30, and 25 o'clock get clamped into range.
+        for (time, date, clamped) in [
+            (0, 0x5001, "2020-01-01 00:00:00"),
+            (0, 0x5020, "2020-01-01 00:00:00"),
+            (0, 0x525e, "2021-02-28 00:00:00"),
+            (0xc800, 0x5021, "2020-01-01 23:00:00"),
+            (0xffff, 0xffff, "2107-12-31 23:59:59"),
+        ] {
+            assert_eq!(parse_msdos(time, date).to_string(), clamped);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Runs the fuzz targets' checks over a corpus of archives that used to
+//! crash piz (panics, aborts, and the like), so they keep not doing that.
+//!
+//! To add to it, minimize a crash from `cargo fuzz` (see `fuzz/`)
+//! with `cargo fuzz tmin`, and drop the result in `tests/corpus/`.
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use anyhow::{Context, Result};
+
+use piz::read::*;
+use piz::result::*;
+
+/// What the `open`, `tree`, `read`, and `recover` fuzz targets do, all in a row,
+/// returning every error along the way.
+fn exercise(data: &[u8]) -> Vec<ZipError> {
+    let mut errors = Vec::new();
+    let mut check = |result: ZipResult<()>| {
+        if let Err(e) = result {
+            errors.push(e);
+        }
+    };
+
+    let (recovered, _report) = ZipArchive::recover(data);
+    for entry in recovered.entries() {
+        check(recovered.read_to_vec(entry).map(drop));
+    }
+
+    let options = OpenOptions::new().max_entries(64);
+    let (archive, _offset) = match ZipArchive::with_options(data, &options) {
+        Ok(opened) => opened,
+        Err(e) => {
+            check(Err(e));
+            return errors;
+        }
+    };
+
+    let sanitized = sanitize_entries(archive.entries()).map(|(entries, _rewrites)| entries);
+    for entries in [Ok(archive.entries().to_vec()), sanitized] {
+        check(entries.and_then(|entries| {
+            let tree = as_tree_with(
+                &entries,
+                &TreeOptions::new().duplicates(DuplicatePolicy::KeepAll),
+            )?;
+            for entry in tree.traverse() {
+                tree.lookup_versions(&entry.metadata().path)?;
+            }
+            let index = CaseInsensitiveIndex::new(&tree);
+            for file in tree.files() {
+                index.lookup(&file.path)?;
+            }
+            Ok(())
+        }));
+    }
+
+    for failure in archive.test().failures {
+        check(Err(failure.error));
+    }
+    for entry in archive.entries() {
+        check(
+            archive
+                .read(entry)
+                .and_then(|mut reader| Ok(io::copy(&mut reader, &mut io::sink())?))
+                .map(drop),
+        );
+        check(archive.read_to_vec(entry).map(drop));
+        check(archive.read_seekable(entry).and_then(|mut reader| {
+            reader.seek(SeekFrom::End(0))?;
+            reader.seek(SeekFrom::Start(0))?;
+            reader.read_to_end(&mut Vec::new())?;
+            Ok(())
+        }));
+    }
+    check(
+        archive
+            .with_limits(Limits::new().max_total_size(1024).max_ratio(100))
+            .map(drop),
+    );
+    errors
+}
+
+#[test]
+fn regressions() -> Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+
+    let mut paths: Vec<_> = fs::read_dir("tests/corpus")?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    for path in &paths {
+        let data = fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+        let errors = exercise(&data);
+        println!("{}: {:?}", path.display(), errors);
+        // Everything here is broken, except for a sanity check
+        // and a bad timestamp (which we clamp instead of failing on).
+        assert_eq!(
+            errors.is_empty(),
+            path.ends_with("valid.zip") || path.ends_with("dos-month-zero.zip"),
+            "{}",
+            path.display()
+        );
+    }
+    Ok(())
+}
@@ -10,9 +10,26 @@ cd $1/tests/inputs
 # Hello Zip archive (small text files)
 zip -r9 hello.zip hello/
 
+# The same, without compression
+zip -r0 hello-stored.zip hello/
+
+# The same, written as if streamed (with data descriptors after each file)
+zip -r9 -fd hello-streamed.zip hello/
+
 # An archive with some junk in the front
 echo "Some junk up front" | cat - hello.zip > hello-prefixed.zip
 
+# Lots of lines: enough to seek around in, and to copy in several chunks
+mkdir lines
+seq 1 2000000 > lines/numbers.txt
+zip -r9 lines.zip lines/
+zip -r0 lines-stored.zip lines/
+
+# 64 KiB of zeros, for checking Deflate streams against declared sizes
+mkdir zeros
+head -c 65536 /dev/zero > zeros/zero64k
+zip -9 zeros.zip zeros/zero64k
+
 # Create a Zip64 archive (one with files too large for original 32-bit fields)
 mkdir zip64
 truncate -s 100M zip64/zero100
@@ -1,23 +1,33 @@
 use std::env;
 use std::fs::File;
-use std::io;
+use std::io::{self, Read, Seek, SeekFrom};
 use std::process::Command;
+#[cfg(feature = "parallel")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "parallel")]
+use std::sync::Mutex;
+use std::thread;
 
 use anyhow::{Context, Result};
 use camino::Utf8Path;
 use log::*;
 use memmap2::Mmap;
 
-use rayon::prelude::*;
-
 use piz::read::*;
 use piz::result::ZipError;
+use piz::{CompressionMethod, OwnedZipArchive};
 
 #[test]
 fn smoke() -> Result<()> {
     let _ = env_logger::builder().is_test(true).try_init();
 
-    let mut inputs = vec!["tests/inputs/hello.zip", "tests/inputs/hello-prefixed.zip"];
+    let mut inputs = vec![
+        "tests/inputs/hello.zip",
+        "tests/inputs/hello-stored.zip",
+        "tests/inputs/hello-streamed.zip",
+        "tests/inputs/hello-prefixed.zip",
+    ];
     // Skip zip64 mmap failure on 32-bit systems.
     if std::mem::size_of::<usize>() >= 8 {
         inputs.push("tests/inputs/zip64.zip");
@@ -34,6 +44,27 @@ fn smoke() -> Result<()> {
     for input in &inputs {
         read_zip(input)?;
     }
+    read_owned_zip("tests/inputs/hello-prefixed.zip")?;
+    seek_zip()?;
+    read_stored_zip()?;
+    #[cfg(feature = "parallel")]
+    parallel_zip()?;
+    test_zip()?;
+    custom_method_zip()?;
+    limit_zip()?;
+    overlap_zip()?;
+    mangle_zip()?;
+    recover_zip()?;
+    commented_zip()?;
+    bad_date_zip()?;
+    extra_field_zip()?;
+    overlong_stream_zip()?;
+    if std::mem::size_of::<usize>() >= 8 {
+        understated_directory_zip()?;
+    }
+    open_zip(&inputs)?;
+    #[cfg(feature = "mmap")]
+    map_zip(&inputs)?;
     tempdir.close()?;
     env::set_current_dir(current_dir)?;
     Ok(())
@@ -52,7 +83,10 @@ fn read_zip(zip_path: &str) -> Result<()> {
     let tree = as_tree(archive.entries())?;
 
     match zip_path {
-        "tests/inputs/hello.zip" | "tests/inputs/hello-prefixed.zip" => {
+        "tests/inputs/hello.zip"
+        | "tests/inputs/hello-stored.zip"
+        | "tests/inputs/hello-streamed.zip"
+        | "tests/inputs/hello-prefixed.zip" => {
             tree.lookup("hello/hi.txt")?;
             tree.lookup("hello/rip.txt")?;
             tree.lookup("hello/sr71.txt")?;
@@ -91,13 +125,843 @@ fn read_zip(zip_path: &str) -> Result<()> {
     // Try reading out each file in the archive.
     // (When the reader gets dropped, the file's CRC32 will be checked
     // against the one stored in the archive.)
-    tree.files()
+    #[cfg(feature = "parallel")]
+    read_zip_in_parallel(&archive, &tree)?;
+    #[cfg(not(feature = "parallel"))]
+    for entry in tree.files() {
+        io::copy(&mut archive.read(entry)?, &mut io::sink())?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "parallel")]
+fn read_zip_in_parallel(archive: &ZipArchive, tree: &DirectoryContents) -> Result<()> {
+    // Progress reports can race each other, so just keep the furthest-along one.
+    let furthest = Mutex::new(Progress::default());
+    let options = ParallelOptions::new().threads(4).progress(|p| {
+        let mut furthest = furthest.lock().unwrap();
+        furthest.bytes_done = furthest.bytes_done.max(p.bytes_done);
+        furthest.entries_done = furthest.entries_done.max(p.entries_done);
+        furthest.bytes_total = p.bytes_total;
+        furthest.entries_total = p.entries_total;
+    });
+    archive.read_parallel(tree.files(), &options, |_entry, reader| {
+        io::copy(reader, &mut io::sink())?;
+        Ok(())
+    })?;
+    drop(options);
+    let furthest = furthest.into_inner().unwrap();
+    assert_eq!(furthest.entries_done, tree.files().count());
+    assert_eq!(furthest.entries_done, furthest.entries_total);
+    assert_eq!(furthest.bytes_done, furthest.bytes_total);
+    Ok(())
+}
+
+fn read_owned_zip(zip_path: &str) -> Result<()> {
+    let bytes: Arc<[u8]> = std::fs::read(zip_path)?.into();
+    let (archive, _offset) = OwnedZipArchive::with_prepended_data(bytes)?;
+    let archive = Arc::new(archive);
+    let hi = archive.tree().lookup("hello/hi.txt")?;
+
+    // Seekable readers should see the same bytes as regular ones.
+    let mut contents = Vec::new();
+    archive.read(hi)?.read_to_end(&mut contents)?;
+    let mut seekable = archive.read_seekable(hi)?;
+    let mut tail = Vec::new();
+    seekable.seek(SeekFrom::End(-3))?;
+    seekable.read_to_end(&mut tail)?;
+    assert_eq!(tail, contents[contents.len() - 3..]);
+
+    // Everything else goes through the borrowed archive.
+    assert_eq!(archive.archive().read_to_vec(hi)?, contents);
+    assert!(archive.archive().test().is_ok());
+
+    // Readers outlive the archive (and any borrow of it),
+    // and the archive itself can be shared between threads.
+    let readers = archive
+        .tree()
+        .files()
         .map(|e| archive.read(e))
-        .par_bridge()
-        .try_for_each::<_, Result<()>>(|reader| {
-            let mut sink = io::sink();
-            io::copy(&mut reader?, &mut sink)?;
+        .collect::<Result<Vec<_>, _>>()?;
+    let shared = archive.clone();
+    drop(archive);
+    let handles: Vec<_> = readers
+        .into_iter()
+        .map(|mut reader| thread::spawn(move || io::copy(&mut reader, &mut io::sink())))
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    thread::spawn(move || shared.tree().files().count())
+        .join()
+        .unwrap();
+    Ok(())
+}
+
+#[cfg(feature = "parallel")]
+fn parallel_zip() -> Result<()> {
+    let bytes = std::fs::read("tests/inputs/hello.zip")?;
+    let archive = ZipArchive::new(&bytes)?;
+    let tree = as_tree(archive.entries())?;
+
+    // One thread reads in the order we asked for, or biggest files first.
+    let read_order = |schedule| -> Result<Vec<usize>> {
+        let sizes = Mutex::new(Vec::new());
+        let options = ParallelOptions::new().threads(1).schedule(schedule);
+        archive.read_parallel(tree.files(), &options, |entry, _reader| {
+            sizes.lock().unwrap().push(entry.size);
             Ok(())
         })?;
+        Ok(sizes.into_inner().unwrap())
+    };
+    let in_order: Vec<_> = tree.files().map(|f| f.size).collect();
+    assert_eq!(read_order(Schedule::InOrder)?, in_order);
+    let mut largest_first = in_order;
+    largest_first.sort_unstable_by(|a, b| b.cmp(a));
+    assert_eq!(read_order(Schedule::LargestFirst)?, largest_first);
+
+    // Errors stop reading.
+    let read_with = |threads| {
+        let read = AtomicUsize::new(0);
+        let result = archive.read_parallel(
+            tree.files(),
+            &ParallelOptions::new().threads(threads),
+            |_entry, _reader| match read.fetch_add(1, Ordering::Relaxed) {
+                1 => Err(ZipError::InvalidArchive("Oh no")),
+                _ => Ok(()),
+            },
+        );
+        assert!(matches!(result, Err(ZipError::InvalidArchive("Oh no"))));
+        read.into_inner()
+    };
+    // Other workers might finish everything else before they notice,
+    read_with(4);
+    // but one worker shouldn't go past it.
+    assert_eq!(read_with(1), 2);
+
+    // Big stored files are copied in chunks, and checked all the same.
+    let mut stored = std::fs::read("tests/inputs/lines-stored.zip")?;
+    let archive = ZipArchive::new(&stored)?;
+    let tree = as_tree(archive.entries())?;
+    let numbers = tree.lookup("lines/numbers.txt")?;
+    let options = ParallelOptions::new().threads(4);
+    let mut copied = vec![0; numbers.size];
+    archive.read_stored_parallel(numbers, &mut copied, &options)?;
+    assert!(copied == archive.stored_bytes(numbers)?);
+    drop(tree);
+
+    let entry = directory_entries(&stored)
+        .into_iter()
+        .find(|e| e.path == "lines/numbers.txt")
+        .unwrap();
+    flip_crc32(&mut stored, &entry);
+    let archive = ZipArchive::new(&stored)?;
+    let numbers = &archive.entries()[entry.index];
+    assert!(matches!(
+        archive.read_stored_parallel(numbers, &mut copied, &options),
+        Err(e) if matches!(e.kind(), ZipError::ChecksumMismatch { .. })
+    ));
+
+    let compressed = std::fs::read("tests/inputs/lines.zip")?;
+    let archive = ZipArchive::new(&compressed)?;
+    let numbers = as_tree(archive.entries())?.lookup("lines/numbers.txt")?;
+    assert!(matches!(
+        archive.read_stored_parallel(numbers, &mut copied, &options),
+        Err(e) if matches!(e.kind(), ZipError::NotStored)
+    ));
+    Ok(())
+}
+
+fn seek_zip() -> Result<()> {
+    let contents = std::fs::read("tests/inputs/lines/numbers.txt")?;
+    for input in ["tests/inputs/lines.zip", "tests/inputs/lines-stored.zip"] {
+        let bytes = std::fs::read(input)?;
+        let archive = ZipArchive::new(&bytes)?;
+        let tree = as_tree(archive.entries())?;
+        let numbers = tree.lookup("lines/numbers.txt")?;
+        check_seeking(&mut archive.read_seekable(numbers)?, &contents)?;
+
+        // Indexes survive a round trip through their serialized form,
+        // and seeking with them gets the same bytes.
+        let index = archive.read_seekable(numbers)?.build_index(1024 * 1024)?;
+        assert_eq!(index.is_empty(), input == "tests/inputs/lines-stored.zip");
+        let mut serialized = Vec::new();
+        index.write_to(&mut serialized)?;
+        assert_eq!(SeekIndex::read_from(&mut serialized.as_slice())?, index);
+        let index = Arc::new(index);
+        let mut reader = archive.read_seekable(numbers)?.with_index(index.clone())?;
+        check_seeking(&mut reader, &contents)?;
+
+        // Indexes are for one file only.
+        let hello = std::fs::read("tests/inputs/hello.zip")?;
+        let hello_archive = ZipArchive::new(&hello)?;
+        let hi = as_tree(hello_archive.entries())?
+            .lookup("hello/hi.txt")?
+            .clone();
+        assert!(hello_archive
+            .read_seekable(&hi)?
+            .with_index(index.clone())
+            .is_err());
+
+        if !index.is_empty() {
+            // Mangled indexes are caught.
+            // (Set the first checkpoint's num_bits to something impossible.)
+            serialized[8 + 4 + 8 + 8 + 8 + 16] = 8;
+            assert!(SeekIndex::read_from(&mut serialized.as_slice()).is_err());
+            serialized.truncate(100);
+            assert!(SeekIndex::read_from(&mut serialized.as_slice()).is_err());
+        }
+    }
+
+    // Seeking around doesn't dodge the CRC check...
+    let mut bytes = std::fs::read("tests/inputs/lines.zip")?;
+    let entry = directory_entries(&bytes)
+        .into_iter()
+        .find(|e| e.path == "lines/numbers.txt")
+        .unwrap();
+    flip_crc32(&mut bytes, &entry);
+    {
+        let archive = ZipArchive::new(&bytes)?;
+        let mut reader = archive.read_seekable(&archive.entries()[entry.index])?;
+        read_exact_at(&mut reader, SeekFrom::Start(5000), 10)?;
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    // ...and neither does lying about the size.
+    flip_crc32(&mut bytes, &entry);
+    let size = contents.len() as u32 + 1;
+    patch_entry(&mut bytes, &entry, 24, &size.to_le_bytes());
+    let archive = ZipArchive::new(&bytes)?;
+    let mut reader = archive.read_seekable(&archive.entries()[entry.index])?;
+    assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    Ok(())
+}
+
+fn read_exact_at(reader: &mut SeekableReader, pos: SeekFrom, len: usize) -> io::Result<Vec<u8>> {
+    reader.seek(pos)?;
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Seeks around the reader, making sure we land where we expect.
+fn check_seeking(reader: &mut SeekableReader, contents: &[u8]) -> io::Result<()> {
+    let len = contents.len();
+    // Forwards, backwards (in and out of the window), and from the end.
+    assert_eq!(
+        read_exact_at(reader, SeekFrom::Start(1000), 100)?,
+        contents[1000..1100]
+    );
+    assert_eq!(
+        read_exact_at(reader, SeekFrom::Start(len as u64 / 2), 70_000)?,
+        contents[len / 2..len / 2 + 70_000]
+    );
+    assert_eq!(
+        read_exact_at(reader, SeekFrom::Current(-1000), 10)?,
+        contents[len / 2 + 69_000..len / 2 + 69_010]
+    );
+    assert_eq!(
+        read_exact_at(reader, SeekFrom::Start(10), 10)?,
+        contents[10..20]
+    );
+    assert_eq!(
+        read_exact_at(reader, SeekFrom::End(-5), 5)?,
+        contents[len - 5..]
+    );
+
+    // Reading to the end checks the CRC.
+    reader.rewind()?;
+    let mut all = Vec::new();
+    reader.read_to_end(&mut all)?;
+    assert!(all == contents);
+
+    assert_eq!(reader.seek(SeekFrom::End(10))?, len as u64 + 10);
+    assert_eq!(reader.read(&mut [0; 10])?, 0);
+    assert!(reader.seek(SeekFrom::Current(-(len as i64) - 11)).is_err());
+    Ok(())
+}
+
+fn read_stored_zip() -> Result<()> {
+    let stored = std::fs::read("tests/inputs/hello-stored.zip")?;
+    let archive = ZipArchive::new(&stored)?;
+    let tree = as_tree(archive.entries())?;
+    let compressed = std::fs::read("tests/inputs/hello.zip")?;
+    let compressed_archive = ZipArchive::new(&compressed)?;
+    let compressed_tree = as_tree(compressed_archive.entries())?;
+
+    for entry in tree.files() {
+        // Stored bytes are borrowed right out of the archive.
+        let bytes = archive.stored_bytes_checked(entry)?;
+        let start = bytes.as_ptr() as usize - stored.as_ptr() as usize;
+        assert!(start + bytes.len() <= stored.len());
+
+        #[cfg(feature = "parallel")]
+        {
+            let mut copied = vec![0; entry.size];
+            archive.read_stored_parallel(entry, &mut copied, &ParallelOptions::new())?;
+            assert_eq!(copied, bytes);
+        }
+
+        let compressed_entry = compressed_tree.lookup(&entry.path)?;
+        let mut decompressed = Vec::new();
+        compressed_archive
+            .read(compressed_entry)?
+            .read_to_end(&mut decompressed)?;
+        assert_eq!(decompressed, bytes);
+        assert_eq!(compressed_archive.read_to_vec(compressed_entry)?, bytes);
+        assert_eq!(archive.read_to_vec(entry)?, bytes);
+        if compressed_entry.compression_method != CompressionMethod::None {
+            assert!(matches!(
+                compressed_archive.stored_bytes(compressed_entry),
+                Err(e) if matches!(e.kind(), ZipError::NotStored)
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn test_zip() -> Result<()> {
+    let mut bytes = std::fs::read("tests/inputs/hello-stored.zip")?;
+    let archive = ZipArchive::new(&bytes)?;
+    let report = archive.test();
+    assert!(report.is_ok());
+    assert_eq!(report.entries_tested, archive.entries().len());
+
+    // Flip a bit in one file and make sure that's the only one that fails.
+    let tree = as_tree(archive.entries())?;
+    let hi = tree.lookup("hello/hi.txt")?;
+    let hi_offset = archive.stored_bytes(hi)?.as_ptr() as usize - bytes.as_ptr() as usize;
+    drop(tree);
+    drop(archive);
+    bytes[hi_offset] ^= 1;
+
+    let archive = ZipArchive::new(&bytes)?;
+    let report = archive.test();
+    assert_eq!(report.failures.len(), 1);
+    let failure = &report.failures[0];
+    assert_eq!(failure.entry.path.as_str(), "hello/hi.txt");
+    assert!(matches!(
+        failure.error.kind(),
+        ZipError::ChecksumMismatch { .. }
+    ));
+    // Errors say which entry they're about, and where.
+    let context = failure.error.context().unwrap();
+    assert_eq!(context.path.as_ref().unwrap(), "hello/hi.txt");
+    let index = archive
+        .entries()
+        .iter()
+        .position(|e| e.path == failure.entry.path);
+    assert_eq!(context.entry, index);
+    assert!(context.offset.unwrap() < hi_offset as u64);
+    Ok(())
+}
+
+/// Very secure
+struct Xor;
+
+struct XorReader<'a>(Box<dyn io::Read + Send + 'a>);
+
+impl io::Read for XorReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.0.read(buf)?;
+        buf[..count].iter_mut().for_each(|b| *b ^= 0x5a);
+        Ok(count)
+    }
+}
+
+impl Decoder for Xor {
+    fn decode<'a>(
+        &self,
+        compressed: Box<dyn io::Read + Send + 'a>,
+    ) -> io::Result<Box<dyn io::Read + Send + 'a>> {
+        Ok(Box::new(XorReader(compressed)))
+    }
+}
+
+fn custom_method_zip() -> Result<()> {
+    let mut bytes = std::fs::read("tests/inputs/hello-stored.zip")?;
+    let entry = directory_entries(&bytes)
+        .into_iter()
+        .find(|e| e.path == "hello/hi.txt")
+        .unwrap();
+    let archive = ZipArchive::new(&bytes)?;
+    let contents = archive.stored_bytes(&archive.entries()[entry.index])?;
+    let data_offset = contents.as_ptr() as usize - bytes.as_ptr() as usize;
+    let contents = contents.to_vec();
+    drop(archive);
+
+    // "Compress" hi.txt with a method only we know.
+    for b in &mut bytes[data_offset..data_offset + contents.len()] {
+        *b ^= 0x5a;
+    }
+    patch_entry(&mut bytes, &entry, 10, &0xf00du16.to_le_bytes());
+    {
+        let archive = ZipArchive::new(&bytes)?.with_decoders(Decoders::new().register(0xf00d, Xor));
+        let hi = &archive.entries()[entry.index];
+        assert_eq!(
+            hi.compression_method,
+            CompressionMethod::Unsupported(0xf00d)
+        );
+        let mut decoded = Vec::new();
+        archive.read(hi)?.read_to_end(&mut decoded)?;
+        assert_eq!(decoded, contents);
+        assert_eq!(archive.read_to_vec(hi)?, contents);
+
+        // Without a decoder, we're out of luck.
+        let archive = ZipArchive::new(&bytes)?;
+        assert!(matches!(
+            archive.read(&archive.entries()[entry.index]),
+            Err(e) if matches!(e.kind(), ZipError::UnsupportedMethod(0xf00d))
+        ));
+    }
+
+    // We still check CRCs...
+    flip_crc32(&mut bytes, &entry);
+    {
+        let archive = ZipArchive::new(&bytes)?.with_decoders(Decoders::new().register(0xf00d, Xor));
+        let mut reader = archive.read(&archive.entries()[entry.index])?;
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    // ...and sizes.
+    flip_crc32(&mut bytes, &entry);
+    let size = contents.len() as u32 + 1;
+    patch_entry(&mut bytes, &entry, 24, &size.to_le_bytes());
+    let archive = ZipArchive::new(&bytes)?.with_decoders(Decoders::new().register(0xf00d, Xor));
+    let hi = &archive.entries()[entry.index];
+    assert!(archive.read(hi)?.read_to_end(&mut Vec::new()).is_err());
+    assert!(matches!(
+        archive.read_to_vec(hi),
+        Err(e) if matches!(e.kind(), ZipError::InvalidArchive(_))
+    ));
     Ok(())
 }
+
+fn limit_zip() -> Result<()> {
+    let bytes = std::fs::read("tests/inputs/hello.zip")?;
+    let total: u64 = ZipArchive::new(&bytes)?
+        .entries()
+        .iter()
+        .map(|e| e.size as u64)
+        .sum();
+    let archive = ZipArchive::new(&bytes)?.with_limits(
+        Limits::new()
+            .max_entries(100)
+            .max_total_size(total)
+            .max_ratio(100),
+    )?;
+
+    // We can read everything once, but that's our budget.
+    let tree = as_tree(archive.entries())?;
+    for file in tree.files() {
+        archive.read_to_vec(file)?;
+    }
+    let first_file = tree.files().find(|f| f.size > 0).unwrap();
+    assert!(matches!(
+        archive.read(first_file),
+        Err(e) if matches!(e.kind(), ZipError::LimitExceeded(_))
+    ));
+
+    // Testing draws from the same budget.
+    let report = archive.test();
+    assert!(!report.is_ok());
+    assert!(report
+        .failures
+        .iter()
+        .all(|f| matches!(f.error.kind(), ZipError::LimitExceeded(_))));
+    let limited = ZipArchive::new(&bytes)?.with_limits(Limits::new().max_total_size(total))?;
+    assert!(limited.test().is_ok());
+    assert!(!limited.test().is_ok());
+
+    // Owned archives share one budget between their readers and the borrowed archive's.
+    let owned = OwnedZipArchive::new(Arc::new(bytes.clone()))?
+        .with_limits(Limits::new().max_total_size(total))?;
+    assert!(owned.archive().test().is_ok());
+    let first_file = owned.tree().files().find(|f| f.size > 0).unwrap();
+    assert!(matches!(
+        owned.read(first_file),
+        Err(e) if matches!(e.kind(), ZipError::LimitExceeded(_))
+    ));
+
+    for stingy in [
+        Limits::new().max_entries(1),
+        Limits::new().max_total_size(total - 1),
+        // sr71.txt compresses about 2:1.
+        Limits::new().max_ratio(1),
+    ] {
+        assert!(matches!(
+            ZipArchive::new(&bytes)?.with_limits(stingy),
+            Err(ZipError::LimitExceeded(_))
+        ));
+    }
+
+    // Opening can check the same entry limit, before reading any entries.
+    let options = OpenOptions::from(Limits::new().max_entries(1).max_ratio(1));
+    assert_eq!(options, OpenOptions::new().max_entries(1));
+    assert!(matches!(
+        ZipArchive::with_options(&bytes, &options),
+        Err(e) if matches!(e.kind(), ZipError::LimitExceeded(_))
+    ));
+
+    // Limits on opening are checked before entries are even parsed.
+    let generous = OpenOptions::new()
+        .max_entries(100)
+        .max_central_directory_size(64 * 1024)
+        .max_path_length(256)
+        .max_extra_field_size(256);
+    ZipArchive::with_options(&bytes, &generous)?;
+    for stingy in [
+        generous.max_entries(1),
+        generous.max_central_directory_size(10),
+        generous.max_path_length(6),
+        generous.max_extra_field_size(0),
+    ] {
+        assert!(matches!(
+            ZipArchive::with_options(&bytes, &stingy),
+            Err(e) if matches!(e.kind(), ZipError::LimitExceeded(_))
+        ));
+    }
+    Ok(())
+}
+
+fn overlap_zip() -> Result<()> {
+    let bytes = std::fs::read("tests/inputs/hello-stored.zip")?;
+    ZipArchive::new(&bytes)?.with_limits(Limits::new())?;
+    let mut entries = directory_entries(&bytes);
+    entries.sort_unstable_by_key(|e| e.local);
+    let (first, last) = (&entries[0], &entries[entries.len() - 1]);
+    // Opening these is fine; it's limits that check for overlaps.
+    let overlapping = |bytes: &[u8]| -> Result<bool> {
+        Ok(matches!(
+            ZipArchive::new(bytes)?.with_limits(Limits::new()),
+            Err(ZipError::InvalidArchive(_))
+        ))
+    };
+
+    // A bomb: the same data, over and over.
+    let mut bomb = bytes.clone();
+    let local = (first.local as u32).to_le_bytes();
+    bomb[last.central + 42..last.central + 46].copy_from_slice(&local);
+    assert!(overlapping(&bomb)?);
+
+    // Or an entry whose data runs into the next one's header...
+    let grow = |bytes: &mut Vec<u8>, entry: &RawEntry, by: u32| {
+        let size = u32::from_le_bytes(
+            bytes[entry.central + 20..entry.central + 24]
+                .try_into()
+                .unwrap(),
+        );
+        for offset in [20, 24] {
+            patch_entry(bytes, entry, offset, &(size + by).to_le_bytes());
+        }
+    };
+    let mut long_first = bytes.clone();
+    grow(&mut long_first, first, 1);
+    assert!(overlapping(&long_first)?);
+    // ...or past the end of the archive.
+    let mut long_last = bytes.clone();
+    grow(&mut long_last, last, bytes.len() as u32);
+    assert!(overlapping(&long_last)?);
+    Ok(())
+}
+
+/// Reads every entry of the given (possibly bogus) archive,
+/// making sure that only ever fails with an error.
+fn read_everything(bytes: &[u8]) {
+    let Ok((archive, _offset)) = ZipArchive::with_prepended_data(bytes) else {
+        return;
+    };
+    let _ = as_tree(archive.entries());
+    for entry in archive.entries() {
+        if let Ok(mut reader) = archive.read(entry) {
+            let _ = io::copy(&mut reader, &mut io::sink());
+        }
+        let _ = archive.read_to_vec(entry);
+    }
+    let _ = archive.test();
+}
+
+/// Like `read_everything()`, but for whatever we can recover from the archive.
+fn recover_everything(bytes: &[u8]) {
+    let (archive, _report) = ZipArchive::recover(bytes);
+    for entry in archive.entries() {
+        let _ = archive.read_to_vec(entry);
+    }
+    let _ = archive.test();
+}
+
+fn mangle_zip() -> Result<()> {
+    let bytes = std::fs::read("tests/inputs/hello.zip")?;
+    // Chop the archive off at every length,
+    for len in 0..bytes.len() {
+        read_everything(&bytes[..len]);
+        recover_everything(&bytes[..len]);
+    }
+    // and mess up every byte of it.
+    let mut mangled = bytes.clone();
+    for i in 0..bytes.len() {
+        for garbage in [0x00, 0xff, bytes[i] ^ 0x80] {
+            mangled[i] = garbage;
+            read_everything(&mangled);
+            recover_everything(&mangled);
+        }
+        mangled[i] = bytes[i];
+    }
+    Ok(())
+}
+
+fn recover_zip() -> Result<()> {
+    for input in [
+        "tests/inputs/hello.zip",
+        "tests/inputs/hello-streamed.zip",
+        "tests/inputs/hello-prefixed.zip",
+    ] {
+        let bytes = std::fs::read(input)?;
+        let (archive, _offset) = ZipArchive::with_prepended_data(&bytes)?;
+
+        // Lop off the central directory, and we should still get everything back.
+        let central_directory = bytes.windows(4).position(|w| w == b"PK\x01\x02").unwrap();
+        let truncated = &bytes[..central_directory];
+        assert!(ZipArchive::with_prepended_data(truncated).is_err());
+        let (recovered, report) = ZipArchive::recover(truncated);
+        assert!(report.is_ok());
+        assert_eq!(report.recovered, archive.entries().len());
+        assert!(recovered.test().is_ok());
+        for (original, salvaged) in archive.entries().iter().zip(recovered.entries()) {
+            assert_eq!(original.path, salvaged.path);
+            assert_eq!(
+                archive.read_to_vec(original)?,
+                recovered.read_to_vec(salvaged)?
+            );
+        }
+
+        // Cut into the last file, and we should get everything before it.
+        let (recovered, report) = ZipArchive::recover(&bytes[..central_directory - 1]);
+        assert_eq!(recovered.entries().len(), archive.entries().len() - 1);
+        assert_eq!(report.failures.len(), 1);
+    }
+    Ok(())
+}
+
+fn commented_zip() -> Result<()> {
+    let bytes = std::fs::read("tests/inputs/hello.zip")?;
+    let archive = ZipArchive::new(&bytes)?;
+
+    // Comment the archive with a copy of its own End of central directory record,
+    // which shouldn't be mistaken for the real one.
+    let eocdr_posit = bytes.len() - 22;
+    let eocdr = bytes[eocdr_posit..].to_vec();
+    let mut commented = bytes.clone();
+    commented[eocdr_posit + 20..].copy_from_slice(&22u16.to_le_bytes());
+    commented.extend(&eocdr);
+
+    let commented_archive = ZipArchive::new(&commented)?;
+    assert_eq!(commented_archive.entries().len(), archive.entries().len());
+    for (original, entry) in archive.entries().iter().zip(commented_archive.entries()) {
+        assert_eq!(
+            archive.read_to_vec(original)?,
+            commented_archive.read_to_vec(entry)?
+        );
+    }
+    Ok(())
+}
+
+fn bad_date_zip() -> Result<()> {
+    let mut bytes = std::fs::read("tests/inputs/hello.zip")?;
+    let archive = ZipArchive::new(&bytes)?;
+    let contents = archive.read_to_vec(&archive.entries()[0])?;
+
+    // Give the first entry a date in month 0, in its local header and central directory.
+    let bad_date = 0x5001u16.to_le_bytes();
+    bytes[12..14].copy_from_slice(&bad_date);
+    let central_directory = bytes.windows(4).position(|w| w == b"PK\x01\x02").unwrap();
+    bytes[central_directory + 14..central_directory + 16].copy_from_slice(&bad_date);
+
+    // That's no reason to reject the whole archive.
+    let archive = ZipArchive::new(&bytes)?;
+    let entry = &archive.entries()[0];
+    assert_eq!(entry.last_modified.date().to_string(), "2020-01-01");
+    assert_eq!(archive.read_to_vec(entry)?, contents);
+    Ok(())
+}
+
+fn extra_field_zip() -> Result<()> {
+    let bytes = std::fs::read("tests/inputs/hello.zip")?;
+    let entry = &directory_entries(&bytes)[0];
+    // Info-ZIP gives each entry a 5 byte timestamp field, then an 11 byte Unix one.
+    let extra_field = entry.central + 46 + entry.path.len();
+    assert_eq!(&bytes[extra_field..extra_field + 4], b"UT\x05\x00");
+
+    // A field claiming to be longer than what's there
+    let mut long_field = bytes.clone();
+    long_field[extra_field + 2..extra_field + 4].copy_from_slice(&[0xff, 0xff]);
+    assert!(matches!(
+        ZipArchive::new(&long_field),
+        Err(e) if matches!(e.kind(), ZipError::InvalidArchive(_))
+    ));
+
+    // Zip64 info without the values the entry needs
+    let mut short_zip64 = bytes.clone();
+    short_zip64[extra_field..extra_field + 2].copy_from_slice(&[1, 0]);
+    patch_entry(&mut short_zip64, entry, 24, &u32::MAX.to_le_bytes());
+    assert!(ZipArchive::new(&short_zip64).is_err());
+
+    // zipalign-style padding: shorten the Unix field, leaving two bytes after it.
+    let mut padded = bytes.clone();
+    padded[extra_field + 11] = 9;
+    ZipArchive::new(&padded)?;
+    Ok(())
+}
+
+fn overlong_stream_zip() -> Result<()> {
+    let mut bytes = std::fs::read("tests/inputs/zeros.zip")?;
+    // The one local header is right at the start.
+    let header_offset = 0;
+
+    // Claim the file is only the first 32 KiB (with their CRC32),
+    // so its Deflate stream keeps going after the declared size.
+    let half = 32 * 1024u32;
+    let crc32 = crc32fast::hash(&vec![0; half as usize]);
+    let central_directory = bytes.windows(4).position(|w| w == b"PK\x01\x02").unwrap();
+    for (crc_offset, size_offset) in [
+        (header_offset + 14, header_offset + 22),
+        (central_directory + 16, central_directory + 24),
+    ] {
+        bytes[crc_offset..crc_offset + 4].copy_from_slice(&crc32.to_le_bytes());
+        bytes[size_offset..size_offset + 4].copy_from_slice(&half.to_le_bytes());
+    }
+
+    let archive = ZipArchive::new(&bytes)?;
+    let entry = &archive.entries()[0];
+    let mut contents = Vec::new();
+    assert!(archive.read(entry)?.read_to_end(&mut contents).is_err());
+    contents.clear();
+    assert!(archive
+        .read_seekable(entry)?
+        .read_to_end(&mut contents)
+        .is_err());
+    Ok(())
+}
+
+fn understated_directory_zip() -> Result<()> {
+    let mut bytes = std::fs::read("tests/inputs/zip64.zip")?;
+    let options = OpenOptions::new().max_central_directory_size(100);
+    assert!(ZipArchive::with_options(&bytes, &options).is_err());
+
+    // Claim the (Zip64) central directory is small enough to get past the limit.
+    // We shouldn't keep parsing entries past what it says.
+    let zip64_eocdr = bytes.windows(4).rposition(|w| w == b"PK\x06\x06").unwrap();
+    bytes[zip64_eocdr + 40..zip64_eocdr + 48].copy_from_slice(&46u64.to_le_bytes());
+    assert!(matches!(
+        ZipArchive::with_options(&bytes, &options),
+        Err(e) if matches!(e.kind(), ZipError::InvalidArchive(_))
+    ));
+    Ok(())
+}
+
+fn open_zip(inputs: &[&str]) -> Result<()> {
+    for input in inputs {
+        let (archive, _offset) = OwnedZipArchive::open_with_prepended_data(input)?;
+        assert!(!archive.data().is_mapped());
+        let first_file = archive.tree().files().next().unwrap();
+        archive.read(first_file)?;
+    }
+    let limited = OwnedZipArchive::open_with_options(
+        "tests/inputs/hello.zip",
+        &OpenOptions::new().max_entries(1),
+        &TreeOptions::new(),
+    );
+    assert!(matches!(limited, Err(e) if matches!(e.kind(), ZipError::LimitExceeded(_))));
+    match OwnedZipArchive::open("tests/inputs/hello-prefixed.zip") {
+        Err(ZipError::PrependedWithUnknownBytes(_)) => Ok(()),
+        Err(other) => panic!("Got incorrect error from prefixed archive: {:?}", other),
+        Ok(_) => panic!("Opened a prefixed archive without allowing prepended data"),
+    }
+}
+
+#[cfg(feature = "mmap")]
+fn map_zip(inputs: &[&str]) -> Result<()> {
+    for input in inputs {
+        // SAFETY: Nothing modifies the test inputs.
+        let mapped = unsafe { ArchiveFile::open_mapped(input)? };
+        // Only the (big) Zip64 archive should get mapped.
+        assert_eq!(mapped.is_mapped(), *input == "tests/inputs/zip64.zip");
+        assert!(mapped.check_unchanged().is_ok());
+        let (archive, _offset) = OwnedZipArchive::with_prepended_data(Arc::new(mapped))?;
+        let first_file = archive.tree().files().next().unwrap();
+        archive.read(first_file)?;
+    }
+    if !inputs.contains(&"tests/inputs/zip64.zip") {
+        return Ok(());
+    }
+
+    // Truncate a mapped archive. Reads should notice before they touch the missing pages.
+    std::fs::copy("tests/inputs/zip64.zip", "tests/inputs/doomed.zip")?;
+    // SAFETY: We only shrink the file, and make sure nothing reads from it afterwards.
+    let mapped = unsafe { ArchiveFile::open_mapped("tests/inputs/doomed.zip")? };
+    let archive = OwnedZipArchive::new(Arc::new(mapped))?;
+    let first_file = archive.tree().files().next().unwrap();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open("tests/inputs/doomed.zip")?
+        .set_len(1024)?;
+    assert!(archive.data().check_unchanged().is_err());
+    assert!(archive.read(first_file).is_err());
+    assert!(archive.read_seekable(first_file).is_err());
+    assert!(archive.archive().read_to_vec(first_file).is_err());
+    let report = archive.archive().test();
+    assert_eq!(report.failures.len(), report.entries_tested);
+    Ok(())
+}
+
+/// Where to find an entry of an archive, for mangling it
+struct RawEntry {
+    index: usize,
+    path: String,
+    /// The offset of its central directory entry
+    central: usize,
+    /// The offset of its local file header
+    local: usize,
+}
+
+/// Walks the central directory of a (non-Zip64, comment-less) archive.
+fn directory_entries(bytes: &[u8]) -> Vec<RawEntry> {
+    let u16_at = |i: usize| u16::from_le_bytes(bytes[i..i + 2].try_into().unwrap()) as usize;
+    let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap()) as usize;
+    let eocdr = bytes.len() - 22;
+    let mut central = u32_at(eocdr + 16);
+    (0..u16_at(eocdr + 10))
+        .map(|index| {
+            let path_len = u16_at(central + 28);
+            let entry = RawEntry {
+                index,
+                path: String::from_utf8(bytes[central + 46..central + 46 + path_len].to_vec())
+                    .unwrap(),
+                central,
+                local: u32_at(central + 42),
+            };
+            central += 46 + path_len + u16_at(central + 30) + u16_at(central + 32);
+            entry
+        })
+        .collect()
+}
+
+/// Overwrites one of the entry's fields in both its central directory entry
+/// and local file header, given its offset in the former.
+/// (The fields from the compression method to the sizes sit
+/// two bytes earlier in local headers.)
+fn patch_entry(bytes: &mut [u8], entry: &RawEntry, offset: usize, value: &[u8]) {
+    for start in [entry.central + offset, entry.local + offset - 2] {
+        bytes[start..start + value.len()].copy_from_slice(value);
+    }
+}
+
+/// Makes the entry's CRC32 wrong.
+fn flip_crc32(bytes: &mut [u8], entry: &RawEntry) {
+    let crc32 = u32::from_le_bytes(
+        bytes[entry.central + 16..entry.central + 20]
+            .try_into()
+            .unwrap(),
+    );
+    patch_entry(bytes, entry, 16, &(crc32 ^ 1).to_le_bytes());
+}